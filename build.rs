@@ -0,0 +1,85 @@
+//! Embeds the files in `static/` with the `embed-static` feature, see `src/assets.rs`.
+
+fn main() {
+    println!("cargo:rerun-if-changed=static");
+    #[cfg(feature = "embed-static")]
+    embed::generate();
+}
+
+#[cfg(feature = "embed-static")]
+mod embed {
+    use sha2::{Digest, Sha256};
+    use std::{
+        env, fs,
+        io::Write,
+        path::{Path, PathBuf},
+    };
+
+    /// Compressed variants are only kept if they're at most this fraction of the original.
+    const MAX_RATIO: f64 = 0.9;
+
+    /// Writes `$OUT_DIR/assets.rs`, a slice of `Asset`s including the files and their compressed variants.
+    pub fn generate() {
+        let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+        let root = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("static");
+        let mut files = Vec::new();
+        walk(&root, &mut files);
+        files.sort();
+
+        let mut code = String::from("&[\n");
+        for file in files {
+            let path = file
+                .strip_prefix(&root)
+                .unwrap()
+                .components()
+                .map(|c| c.as_os_str().to_str().expect("non-UTF-8 file name"))
+                .collect::<Vec<_>>()
+                .join("/");
+            let content = fs::read(&file).unwrap();
+            let hash = Sha256::digest(&content);
+            let etag: String = hash[..12].iter().map(|b| format!("{b:02x}")).collect();
+            let variant = |ext: &str, compressed: Vec<u8>| {
+                if compressed.len() as f64 > content.len() as f64 * MAX_RATIO {
+                    return "None".to_owned();
+                }
+                let dest = out.join("assets").join(format!("{path}.{ext}"));
+                fs::create_dir_all(dest.parent().unwrap()).unwrap();
+                fs::write(&dest, compressed).unwrap();
+                format!("Some(include_bytes!({:?}))", dest)
+            };
+            let gzip = variant("gz", gzip(&content));
+            let br = variant("br", brotli(&content));
+            code += &format!(
+                "    Asset {{ path: {path:?}, etag: \"W/\\\"{etag}\\\"\", content: include_bytes!({file:?}), gzip: {gzip}, br: {br} }},\n"
+            );
+        }
+        code += "]\n";
+        fs::write(out.join("assets.rs"), code).unwrap();
+    }
+
+    fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                walk(&path, files);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(content: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+            encoder.write_all(content).unwrap();
+        }
+        compressed
+    }
+}
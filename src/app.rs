@@ -0,0 +1,353 @@
+use crate::{
+    assets, cancel_transfer,
+    cleanup::Cleanup,
+    compression::Compression,
+    config::Config,
+    create_transfer, current_device, decline_transfer, device_inbox,
+    devices::Devices,
+    get_history, get_thumbnail,
+    images::ImageConfig,
+    jwt::{self, EncodeConfig},
+    lifetime::LifetimeConfig,
+    list_devices, metrics,
+    middleware::{JwtDecoder, RequireToken, SameOrigin, Tokens, TrustProxies},
+    openapi::API_VERSION,
+    openapi_json, pair_device,
+    proxy::ProxyConfig,
+    quota::Quotas,
+    receive, receive_info, receive_status, remove_device,
+    scan::Scanner,
+    send, send_ws,
+    sniff::ContentTypeConfig,
+    start_pairing,
+    store::{Store, StoreError},
+    transfer::Transfers,
+    transfer_events, transfer_status,
+    upload::UploadLimits,
+    upload_thumbnail, view, wait_transfer,
+};
+use actix::{Actor, Addr};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    web, App, Scope,
+};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use std::{path::PathBuf, sync::Arc};
+use tracing_actix_web::TracingLogger;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BuildError {
+    #[error("No config was given")]
+    NoConfig,
+    #[error("Couldn't open the database: {0}")]
+    Database(#[from] StoreError),
+    #[error("Invalid jwt-secret: {0}")]
+    JwtSecret(#[from] jsonwebtoken::errors::Error),
+}
+
+/// Builds a [`Frachter`], see [`Frachter::builder`].
+#[derive(Default)]
+pub struct FrachterBuilder {
+    config: Option<Config>,
+}
+
+impl FrachterBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Opens the database and starts the cleanup, so it has to be called within an actix system.
+    pub fn build(self) -> Result<Frachter, BuildError> {
+        let config = self.config.ok_or(BuildError::NoConfig)?;
+        let transfers = Transfers::new();
+        let quotas = Quotas::new(config.quota.clone());
+        let store = match &config.database {
+            Some(path) => Store::open(path)?,
+            None => Store::disabled(),
+        };
+        let devices = Devices::new(store.clone());
+        let mut cleanup = Cleanup::new(
+            transfers.clone(),
+            config.lifetime.status(),
+            store.clone(),
+            devices.clone(),
+        );
+        cleanup.restore(&quotas);
+        let cleanup = cleanup.start();
+
+        Ok(Frachter {
+            encode_config: web::Data::new((
+                EncodingKey::from_base64_secret(&config.jwt_secret)?,
+                jsonwebtoken::Header::default(),
+            )),
+            decode_config: Arc::new((
+                DecodingKey::from_base64_secret(&config.jwt_secret)?,
+                jsonwebtoken::Validation::default(),
+            )),
+            tokens: Arc::new(config.tokens()),
+            transfers: web::Data::new(transfers),
+            scanner: web::Data::new(Scanner::new(&config.scan, &cleanup)),
+            cleanup: web::Data::new(cleanup),
+            upload_limits: web::Data::new(config.upload.limits()),
+            quotas: web::Data::new(quotas),
+            lifetimes: web::Data::new(config.lifetime),
+            store: web::Data::new(store),
+            devices: web::Data::new(devices),
+            compression: web::Data::new(Compression::new(&config.compression)),
+            content_types: web::Data::new(config.content_type),
+            images: web::Data::new(config.images),
+            assets_dir: config.assets.dir(),
+            proxy: web::Data::new(config.proxy),
+        })
+    }
+}
+
+/// The relay, built once and cloned into every worker's app.
+#[derive(Clone)]
+pub struct Frachter {
+    transfers: web::Data<Transfers>,
+    cleanup: web::Data<Addr<Cleanup>>,
+    encode_config: web::Data<EncodeConfig>,
+    decode_config: Arc<jwt::DecodeConfig>,
+    tokens: Arc<Tokens>,
+    upload_limits: web::Data<UploadLimits>,
+    quotas: web::Data<Quotas>,
+    lifetimes: web::Data<LifetimeConfig>,
+    store: web::Data<Store>,
+    devices: web::Data<Devices>,
+    compression: web::Data<Compression>,
+    content_types: web::Data<ContentTypeConfig>,
+    images: web::Data<ImageConfig>,
+    scanner: web::Data<Option<Scanner>>,
+    proxy: web::Data<ProxyConfig>,
+    assets_dir: Option<PathBuf>,
+}
+
+impl Frachter {
+    pub fn builder() -> FrachterBuilder {
+        FrachterBuilder::default()
+    }
+
+    pub fn transfers(&self) -> &Transfers {
+        &self.transfers
+    }
+
+    /// Where the routes are mounted, `""` or e.g. `"/frachter"`.
+    pub fn base_path(&self) -> String {
+        self.proxy.base_path()
+    }
+
+    /// The app the server runs, with request logging.
+    pub fn app(
+        &self,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        let base_path = self.base_path();
+        App::new()
+            .wrap(TracingLogger::default())
+            // before the logger looks at the client's address
+            .wrap(TrustProxies(self.proxy.clone().into_inner()))
+            .configure(|cfg| {
+                // relative URLs in the page only work with the trailing slash
+                if !base_path.is_empty() {
+                    cfg.service(web::redirect(base_path.clone(), format!("{base_path}/")));
+                }
+            })
+            .service(self.routes())
+    }
+
+    /// The API and the web UI at the configured base path, to mount into another app.
+    ///
+    /// Register it after your own services, it serves the web UI for every other path below the
+    /// base path. `Forwarded` headers are only sanitized once the request reaches the scope, so
+    /// middleware of the app sees them as the client sent them.
+    pub fn scope(
+        &self,
+    ) -> Scope<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        self.routes()
+            .wrap(TrustProxies(self.proxy.clone().into_inner()))
+    }
+
+    /// The app of the admin listener, it only serves `/metrics` and doesn't need a token, so it
+    /// should only be reachable by whoever collects them.
+    pub fn admin_app(
+        &self,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .app_data(self.cleanup.clone())
+            .service(metrics::get_metrics)
+    }
+
+    fn routes(&self) -> Scope {
+        let (tokens, decode_config) = (&self.tokens, &self.decode_config);
+        let proxy = self.proxy.clone().into_inner();
+        web::scope(&self.base_path())
+            .app_data(self.transfers.clone())
+            .app_data(self.cleanup.clone())
+            .app_data(self.encode_config.clone())
+            .app_data(self.upload_limits.clone())
+            .app_data(self.quotas.clone())
+            .app_data(self.lifetimes.clone())
+            .app_data(self.store.clone())
+            .app_data(self.compression.clone())
+            .app_data(self.content_types.clone())
+            .app_data(self.images.clone())
+            .app_data(self.scanner.clone())
+            .app_data(self.devices.clone())
+            .app_data(self.proxy.clone())
+            .service(
+                web::scope("/api")
+                    .service(
+                        web::scope(&format!("/{API_VERSION}"))
+                            .configure(|cfg| configure_api(cfg, tokens, decode_config, &proxy)),
+                    )
+                    // unversioned routes for existing clients
+                    .configure(|cfg| configure_api(cfg, tokens, decode_config, &proxy)),
+            )
+            .configure(|cfg| assets::configure(cfg, self.assets_dir.as_deref()))
+    }
+}
+
+/// The API's routes, relative to `/api/v1`.
+fn configure_api(
+    cfg: &mut web::ServiceConfig,
+    token: &Arc<Tokens>,
+    decode_config: &Arc<jwt::DecodeConfig>,
+    proxy: &Arc<ProxyConfig>,
+) {
+    cfg.service(
+        web::scope("/transfers")
+            .wrap(RequireToken(token.clone()))
+            .service(create_transfer),
+    )
+    .service(
+        // EventSource can't set headers, so only the transfer's cookie is checked
+        web::scope("/transfer/events")
+            .wrap(JwtDecoder(decode_config.clone()))
+            .wrap(SameOrigin(proxy.clone()))
+            .service(transfer_events),
+    )
+    .service(
+        // browsers can't set headers on WebSocket requests either
+        web::scope("/transfer/ws")
+            .wrap(JwtDecoder(decode_config.clone()))
+            .wrap(SameOrigin(proxy.clone()))
+            .service(send_ws),
+    )
+    .service(
+        web::scope("/transfer")
+            .wrap(RequireToken(token.clone()))
+            .wrap(JwtDecoder(decode_config.clone()))
+            .service(wait_transfer)
+            .service(cancel_transfer)
+            .service(transfer_status)
+            .service(upload_thumbnail)
+            .service(send),
+    )
+    .service(
+        web::scope("/receive")
+            .service(receive)
+            .service(view)
+            .service(receive_info)
+            .service(receive_status)
+            .service(decline_transfer)
+            .service(get_thumbnail),
+    )
+    .service(
+        web::scope("/devices")
+            .wrap(RequireToken(token.clone()))
+            .service(list_devices)
+            .service(start_pairing)
+            .service(remove_device),
+    )
+    .service(
+        // paired devices authenticate with their own token
+        web::scope("/device")
+            .service(current_device)
+            .service(pair_device)
+            .service(device_inbox),
+    )
+    .service(
+        web::scope("/history")
+            .wrap(RequireToken(token.clone()))
+            .service(get_history),
+    )
+    .service(openapi_json);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::StatusCode,
+        test::{self, TestRequest},
+        HttpResponse,
+    };
+
+    #[actix_web::test]
+    async fn mounts_into_another_app() {
+        let config = toml::from_str(
+            r#"
+            bind = "127.0.0.1:0"
+            token = "token"
+            jwt-secret = "c2VjcmV0"
+            proxy.base-path = "/relay"
+            "#,
+        )
+        .unwrap();
+        let frachter = Frachter::builder().config(config).build().unwrap();
+        let app = test::init_service(
+            App::new()
+                .route("/health", web::get().to(HttpResponse::NoContent))
+                .service(frachter.scope()),
+        )
+        .await;
+
+        let res = test::call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = test::call_service(
+            &app,
+            TestRequest::get()
+                .uri("/relay/api/v1/openapi.json")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(
+            &app,
+            TestRequest::put()
+                .uri("/relay/api/v1/transfers")
+                .insert_header(("x-frachter-token", "token"))
+                .set_json(serde_json::json!({ "filename": "a.txt" }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(frachter.transfers().0.lock().unwrap().len(), 1);
+    }
+}
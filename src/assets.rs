@@ -0,0 +1,193 @@
+use actix_files::Files;
+use actix_web::{
+    http::header::{self, AcceptEncoding, ContentEncoding, Encoding},
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct AssetsConfig {
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+}
+
+impl AssetsConfig {
+    pub fn dir(&self) -> Option<PathBuf> {
+        match (&self.dir, cfg!(feature = "embed-static")) {
+            (Some(dir), _) => Some(dir.clone()),
+            (None, true) => None,
+            (None, false) => Some("static".into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Asset {
+    /// Relative to `static/`, separated by `/`.
+    pub path: &'static str,
+    pub etag: &'static str,
+    pub content: &'static [u8],
+    pub gzip: Option<&'static [u8]>,
+    pub br: Option<&'static [u8]>,
+}
+
+#[cfg(feature = "embed-static")]
+static EMBEDDED: &[Asset] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+#[cfg(not(feature = "embed-static"))]
+static EMBEDDED: &[Asset] = &[];
+
+pub fn configure(cfg: &mut web::ServiceConfig, dir: Option<&Path>) {
+    match dir {
+        Some(dir) => cfg.service(
+            Files::new("/", dir)
+                .prefer_utf8(true)
+                .use_etag(false)
+                .index_file("index.html"),
+        ),
+        None => cfg.service(
+            web::resource("/{path:.*}")
+                .route(web::get().to(serve))
+                .route(web::head().to(serve)),
+        ),
+    };
+}
+
+/// The embedded file at `path`, directories map to their `index.html`.
+pub fn find(path: &str) -> Option<&'static Asset> {
+    let path = path.trim_start_matches('/');
+    let index;
+    let path = match path.is_empty() || path.ends_with('/') {
+        true => {
+            index = format!("{path}index.html");
+            &index
+        }
+        false => path,
+    };
+    EMBEDDED.iter().find(|asset| asset.path == path)
+}
+
+async fn serve(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+    let Some(asset) = find(&path) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mut res = HttpResponse::Ok();
+    // the files aren't fingerprinted, so browsers have to revalidate them
+    res.insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header((header::ETAG, asset.etag))
+        .insert_header((header::VARY, "accept-encoding"));
+    let fresh = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| matches!(tag.trim(), "*") || weak_eq(tag.trim(), asset.etag))
+        });
+    if fresh {
+        return res
+            .status(actix_web::http::StatusCode::NOT_MODIFIED)
+            .finish();
+    }
+
+    let accept = req
+        .get_header::<AcceptEncoding>()
+        .unwrap_or(AcceptEncoding(Vec::new()));
+    let variants = [
+        asset.br.map(|br| (ContentEncoding::Brotli, br)),
+        asset.gzip.map(|gzip| (ContentEncoding::Gzip, gzip)),
+    ];
+    let candidates: Vec<_> = variants
+        .iter()
+        .flatten()
+        .map(|(encoding, _)| Encoding::Known(*encoding))
+        .chain([Encoding::identity()])
+        .collect();
+    let chosen = accept.negotiate(candidates.iter());
+    let variant = variants
+        .into_iter()
+        .flatten()
+        .find(|(encoding, _)| chosen == Some(Encoding::Known(*encoding)));
+    res.content_type(content_type(asset.path));
+
+    match variant {
+        Some((encoding, body)) => res
+            .insert_header((header::CONTENT_ENCODING, encoding))
+            .body(body),
+        None => res.body(asset.content),
+    }
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// The type of an asset, text is always UTF-8.
+fn content_type(path: &str) -> String {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    match mime.type_() == mime::TEXT || mime.subtype() == mime::JAVASCRIPT {
+        true => format!("{mime}; charset=utf-8"),
+        false => mime.to_string(),
+    }
+}
+
+#[cfg(all(test, feature = "embed-static"))]
+mod tests {
+    use super::*;
+    use actix_web::{
+        body::MessageBody,
+        http::StatusCode,
+        test::{self, TestRequest},
+        App,
+    };
+
+    #[test]
+    fn finds_embedded_assets() {
+        assert_eq!(find("").unwrap().path, "index.html");
+        assert_eq!(find("/index.js").unwrap().path, "index.js");
+        assert!(find("../Cargo.toml").is_none());
+        assert_eq!(
+            content_type("index.js"),
+            "application/javascript; charset=utf-8"
+        );
+        assert_eq!(content_type("favicon.ico"), "image/x-icon");
+    }
+
+    #[actix_web::test]
+    async fn serves_precompressed_variants() {
+        let app = test::init_service(App::new().configure(|cfg| configure(cfg, None))).await;
+        let index = find("index.html").unwrap();
+
+        let res = test::call_service(
+            &app,
+            TestRequest::get()
+                .uri("/")
+                .insert_header((header::ACCEPT_ENCODING, "gzip, br"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), index.etag);
+        let body = res.into_body().try_into_bytes().unwrap();
+        assert_eq!(body, index.br.unwrap());
+
+        let res = test::call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(res.into_body().try_into_bytes().unwrap(), index.content);
+
+        let res = test::call_service(
+            &app,
+            TestRequest::get()
+                .uri("/")
+                .insert_header((header::IF_NONE_MATCH, index.etag))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = test::call_service(&app, TestRequest::get().uri("/nope").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
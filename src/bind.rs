@@ -0,0 +1,212 @@
+use actix_http::{body::MessageBody, Request, Response};
+use actix_service::{IntoServiceFactory, Service, ServiceFactory};
+use actix_web::{dev::AppConfig, HttpServer};
+use serde::{de, Deserialize, Deserializer};
+use std::{fmt, io, net::SocketAddr, path::PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddress {
+    Tcp(String),
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddress::Tcp(addr) => f.write_str(addr),
+            BindAddress::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl BindAddress {
+    fn parse(address: &str, mode: Option<&str>) -> Result<Self, String> {
+        let mode = mode
+            .map(|mode| {
+                u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or_else(|| format!("bad socket mode {mode:?}, expected e.g. \"660\""))
+            })
+            .transpose()?;
+        match address.strip_prefix("unix:") {
+            Some("") => Err("unix sockets need a path, e.g. unix:/run/frachter.sock".to_owned()),
+            Some(path) => Ok(Self::Unix {
+                path: path.into(),
+                mode,
+            }),
+            None if mode.is_some() => Err(format!(
+                "only unix sockets have a mode, {address} isn't one"
+            )),
+            None => Ok(Self::Tcp(address.to_owned())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Address(String),
+    Detailed {
+        address: String,
+        mode: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entries {
+    One(Entry),
+    Many(Vec<Entry>),
+}
+
+/// Reads one address or a list of them, either strings or tables with an `address` and a `mode`.
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<BindAddress>, D::Error> {
+    let entries = match Entries::deserialize(d)? {
+        Entries::One(entry) => vec![entry],
+        Entries::Many(entries) => entries,
+    };
+    if entries.is_empty() {
+        return Err(de::Error::custom("bind needs at least one address"));
+    }
+    entries
+        .into_iter()
+        .map(|entry| match entry {
+            Entry::Address(address) => BindAddress::parse(&address, None),
+            Entry::Detailed { address, mode } => BindAddress::parse(&address, mode.as_deref()),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(de::Error::custom)
+}
+
+/// Binds `server` to every address, the TCP addresses it listens on are added to `tcp_addrs`.
+pub fn bind<F, I, S, B>(
+    mut server: HttpServer<F, I, S, B>,
+    addresses: &[BindAddress],
+    tcp_addrs: &mut Vec<SocketAddr>,
+) -> io::Result<HttpServer<F, I, S, B>>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    for address in addresses {
+        let bound = match address {
+            BindAddress::Tcp(addr) => {
+                // unix sockets show up as a placeholder in `addrs`
+                let before = server.addrs().len();
+                server.bind(addr).inspect(|server| {
+                    tcp_addrs.extend_from_slice(&server.addrs()[before..]);
+                })
+            }
+            #[cfg(unix)]
+            BindAddress::Unix { path, mode } => {
+                server.bind_uds(path).and_then(|server| match mode {
+                    Some(mode) => set_mode(path, *mode).map(|_| server),
+                    None => Ok(server),
+                })
+            }
+            #[cfg(not(unix))]
+            BindAddress::Unix { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets aren't supported on this platform",
+            )),
+        };
+        server =
+            bound.map_err(|e| io::Error::new(e.kind(), format!("Couldn't bind {address}: {e}")))?;
+    }
+    Ok(server)
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &std::path::Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct Config {
+        #[serde(deserialize_with = "deserialize")]
+        bind: Vec<BindAddress>,
+    }
+
+    fn parse(toml: &str) -> Result<Vec<BindAddress>, toml::de::Error> {
+        toml::from_str::<Config>(toml).map(|c| c.bind)
+    }
+
+    #[test]
+    fn parses_single_addresses_and_lists() {
+        assert_eq!(
+            parse(r#"bind = "127.0.0.1:8080""#).unwrap(),
+            [BindAddress::Tcp("127.0.0.1:8080".to_owned())]
+        );
+        assert_eq!(
+            parse(
+                r#"bind = ["[::]:8080", "unix:/run/frachter.sock", { address = "unix:/tmp/a.sock", mode = "660" }]"#
+            )
+            .unwrap(),
+            [
+                BindAddress::Tcp("[::]:8080".to_owned()),
+                BindAddress::Unix {
+                    path: "/run/frachter.sock".into(),
+                    mode: None
+                },
+                BindAddress::Unix {
+                    path: "/tmp/a.sock".into(),
+                    mode: Some(0o660)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_addresses() {
+        assert!(parse("bind = []").is_err());
+        assert!(parse(r#"bind = "unix:""#).is_err());
+        assert!(parse(r#"bind = [{ address = "unix:/a.sock", mode = "999" }]"#).is_err());
+        assert!(parse(r#"bind = [{ address = "0.0.0.0:80", mode = "660" }]"#).is_err());
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn serves_on_unix_sockets() {
+        use actix_web::{web, App, HttpServer};
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("frachter-{}.sock", uuid::Uuid::new_v4()));
+        let server = HttpServer::new(|| App::new().route("/", web::get().to(|| async { "hi" })))
+            .workers(1)
+            .bind_uds(&path)
+            .unwrap();
+        set_mode(&path, 0o660).unwrap();
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{res}");
+        assert!(res.ends_with("hi"), "{res}");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        handle.stop(false).await;
+        std::fs::remove_file(&path).ok();
+    }
+}
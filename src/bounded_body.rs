@@ -36,24 +36,34 @@ impl<E> Sender<E> {
             })
     }
 
+    /// Completes once the other side of the channel body was dropped.
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+
     /// Closes the stream, optionally sending an error.
     ///
     /// # Errors
     /// Errors if closing with error and other side of channel body was dropped, returning `error`.
-    #[allow(unused)]
     pub async fn close(self, error: Option<E>) -> Result<(), E> {
-        if let Some(err) = error {
-            return self
-                .tx
-                .send(Err(err))
-                .await
-                .map_err(|mpsc::error::SendError(err)| match err {
-                    Ok(_) => unreachable!(), // we always send Err(err)
-                    Err(err) => err,
-                });
+        match error {
+            Some(err) => self.abort(err).await,
+            None => Ok(()),
         }
+    }
 
-        Ok(())
+    /// Ends the stream with `error`, without giving up the sender.
+    ///
+    /// # Errors
+    /// Errors if other side of channel body was dropped, returning `error`.
+    pub async fn abort(&self, error: E) -> Result<(), E> {
+        self.tx
+            .send(Err(error))
+            .await
+            .map_err(|mpsc::error::SendError(err)| match err {
+                Ok(_) => unreachable!(), // we always send Err(err)
+                Err(err) => err,
+            })
     }
 }
 
@@ -0,0 +1,280 @@
+use crate::{
+    images::MetadataStripper,
+    transfer::{TransferAborted, TransferSender},
+};
+use actix_web::http::header::HeaderName;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use utoipa::ToSchema;
+
+pub const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+
+/// Algorithms this server doesn't compute are ignored when verifying.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Digest {
+    #[serde(
+        default,
+        with = "crate::serde_util::base64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, format = Byte)]
+    pub sha256: Option<Vec<u8>>,
+    #[serde(
+        default,
+        with = "crate::serde_util::base64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, format = Byte)]
+    pub blake3: Option<Vec<u8>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("The {0} checksum doesn't match the one declared by the sender")]
+pub struct ChecksumMismatch(pub &'static str);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid digest: {0}")]
+pub struct BadDigest(pub String);
+
+impl Digest {
+    /// Parses a `Repr-Digest` header, e.g. `sha-256=:<base64>:`.
+    pub fn from_header(header: &str) -> Result<Self, BadDigest> {
+        let mut digest = Self::default();
+        for member in header.split(',') {
+            let (algorithm, value) = member
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| BadDigest(member.to_owned()))?;
+            let slot = match algorithm.trim().to_ascii_lowercase().as_str() {
+                "sha-256" => &mut digest.sha256,
+                "blake3" => &mut digest.blake3,
+                // unknown algorithms can be ignored
+                _ => continue,
+            };
+            let value = value
+                .trim()
+                .strip_prefix(':')
+                .and_then(|v| v.strip_suffix(':'))
+                .ok_or_else(|| BadDigest(member.to_owned()))?;
+            *slot = Some(
+                STANDARD
+                    .decode(value)
+                    .map_err(|e| BadDigest(e.to_string()))?,
+            );
+        }
+
+        Ok(digest)
+    }
+
+    pub fn to_header(&self) -> String {
+        [("sha-256", &self.sha256), ("blake3", &self.blake3)]
+            .into_iter()
+            .filter_map(|(algorithm, value)| {
+                Some(format!(
+                    "{algorithm}=:{}:",
+                    STANDARD.encode(value.as_ref()?)
+                ))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sha256.is_none() && self.blake3.is_none()
+    }
+
+    /// Drops the algorithms this server doesn't compute, a digest without any of them can't be
+    /// verified and is rejected.
+    pub fn supported(mut self) -> Result<Self, BadDigest> {
+        if !cfg!(feature = "blake3") {
+            self.blake3 = None;
+        }
+        match self.is_empty() {
+            true => Err(BadDigest(
+                "none of its algorithms is computed by this server".to_owned(),
+            )),
+            false => Ok(self),
+        }
+    }
+
+    pub fn verify(&self, expected: &Digest) -> Result<(), ChecksumMismatch> {
+        for (algorithm, computed, expected) in [
+            ("SHA-256", &self.sha256, &expected.sha256),
+            ("BLAKE3", &self.blake3, &expected.blake3),
+        ] {
+            if let (Some(computed), Some(expected)) = (computed, expected) {
+                if computed != expected {
+                    return Err(ChecksumMismatch(algorithm));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Checksum {
+    sha256: Sha256,
+    #[cfg(feature = "blake3")]
+    blake3: blake3::Hasher,
+}
+
+impl Checksum {
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        #[cfg(feature = "blake3")]
+        self.blake3.update(data);
+    }
+
+    pub fn digest(&self) -> Digest {
+        Digest {
+            sha256: Some(self.sha256.clone().finalize().to_vec()),
+            #[cfg(feature = "blake3")]
+            blake3: Some(self.blake3.finalize().as_bytes().to_vec()),
+            #[cfg(not(feature = "blake3"))]
+            blake3: None,
+        }
+    }
+}
+
+/// The last chunk is held back until the digest is verified,
+/// so the receiver never gets a complete body that doesn't match.
+pub struct HashingSender<'a> {
+    sender: &'a mut TransferSender,
+    checksum: Checksum,
+    held: Option<Bytes>,
+    /// Applied after hashing, the digest covers the payload as it was uploaded.
+    stripper: Option<MetadataStripper>,
+}
+
+impl<'a> HashingSender<'a> {
+    pub fn new(sender: &'a mut TransferSender) -> Self {
+        Self {
+            sender,
+            checksum: Checksum::default(),
+            held: None,
+            stripper: None,
+        }
+    }
+
+    pub fn strip_metadata(&mut self, stripper: MetadataStripper) {
+        self.stripper = Some(stripper);
+    }
+
+    pub async fn send(&mut self, chunk: Bytes) -> Result<(), Bytes> {
+        self.hash(&chunk);
+        self.forward(chunk).await
+    }
+
+    /// Hashes `chunk` without sending it, e.g. while it's held back for a scan.
+    pub fn hash(&mut self, chunk: &[u8]) {
+        self.checksum.update(chunk);
+    }
+
+    /// Sends the previous chunk and holds back `chunk`, which was hashed already.
+    pub async fn forward(&mut self, chunk: Bytes) -> Result<(), Bytes> {
+        let chunk = match &mut self.stripper {
+            Some(stripper) => stripper.push(&chunk),
+            None => chunk,
+        };
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        match self.held.replace(chunk) {
+            Some(previous) => self.sender.send(previous).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn closed(&self) {
+        self.sender.closed().await
+    }
+
+    pub fn verify(&self, expected: Option<&Digest>) -> Result<Digest, ChecksumMismatch> {
+        let digest = self.checksum.digest();
+        if let Some(expected) = expected {
+            digest.verify(expected)?;
+        }
+        Ok(digest)
+    }
+
+    pub async fn abort(&self, error: TransferAborted) -> Result<(), TransferAborted> {
+        self.sender.abort(error).await
+    }
+
+    /// Sends the held back chunk, call this once the digest is verified.
+    pub async fn flush(&mut self) -> Result<(), Bytes> {
+        if let Some(last) = self.held.take() {
+            self.sender.send(last).await?;
+        }
+        match self.stripper.as_mut().map(MetadataStripper::finish) {
+            Some(rest) if !rest.is_empty() => self.sender.send(rest).await,
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+
+    #[test]
+    fn computes_sha256_incrementally() {
+        let mut checksum = Checksum::default();
+        checksum.update(b"hel");
+        checksum.update(b"lo");
+        assert_eq!(
+            checksum.digest().to_header().split(',').next(),
+            Some(format!("sha-256=:{HELLO_SHA256}:").as_str())
+        );
+    }
+
+    #[test]
+    fn parses_repr_digest_headers() {
+        let digest =
+            Digest::from_header(&format!("unknown=:AAAA:, SHA-256=:{HELLO_SHA256}:")).unwrap();
+        assert_eq!(digest.sha256, Some(STANDARD.decode(HELLO_SHA256).unwrap()));
+        assert_eq!(digest.blake3, None);
+
+        assert!(Digest::from_header("sha-256=abc").is_err());
+        assert!(Digest::from_header("sha-256=:not base64!:").is_err());
+    }
+
+    #[test]
+    fn rejects_digests_without_supported_algorithms() {
+        let sha256 = Digest::from_header(&format!("sha-256=:{HELLO_SHA256}:")).unwrap();
+        assert_eq!(sha256.clone().supported().unwrap(), sha256);
+        assert!(Digest::from_header("md5=:AAAA:")
+            .unwrap()
+            .supported()
+            .is_err());
+
+        let blake3 = Digest {
+            sha256: None,
+            blake3: Some(vec![0; 32]),
+        };
+        assert_eq!(blake3.supported().is_ok(), cfg!(feature = "blake3"));
+    }
+
+    #[test]
+    fn verifies_only_declared_algorithms() {
+        let mut checksum = Checksum::default();
+        checksum.update(b"hello");
+        let digest = checksum.digest();
+
+        assert!(digest.verify(&Digest::default()).is_ok());
+        assert!(digest
+            .verify(&Digest::from_header(&format!("sha-256=:{HELLO_SHA256}:")).unwrap())
+            .is_ok());
+        let wrong = Digest {
+            sha256: Some(vec![0; 32]),
+            blake3: None,
+        };
+        assert!(digest.verify(&wrong).is_err());
+    }
+}
@@ -1,100 +1,204 @@
-use crate::{mutex::MutexExt, Transfers};
-use actix::{Actor, AsyncContext, Context, Handler, Message, MessageResult, SpawnHandle};
+use crate::{
+    devices::Devices,
+    expiry::ExpiryQueue,
+    history::TransferRecord,
+    metrics::Metrics,
+    quota::Quotas,
+    status::{FailureReason, TransferStatus},
+    store::{Store, StoredStatus},
+    transfer::TransferInfo,
+    Transfers,
+};
+use actix::{Actor, ActorFuture, AsyncContext, Context, Handler, Message, MessageResult};
 use std::{
     collections::HashMap,
-    time::{Duration, Instant},
+    io,
+    path::PathBuf,
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
 };
-use tracing::debug;
+use time::OffsetDateTime;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-const TRANSFER_DURATION: Duration = Duration::from_secs(10 * 60);
-const STATUS_DURATION: Duration = Duration::from_secs(60);
+const SPOOL_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+enum Expiring {
+    Transfer(Uuid),
+    Status(Uuid),
+    Pairing(String),
+    Spool(PathBuf),
+}
 
 pub struct Cleanup {
     transfers: Transfers,
-    statuses: HashMap<Uuid, (Instant, bool)>,
-    pending: Vec<(Uuid, Instant)>,
-
-    pending_handle: Option<SpawnHandle>,
-    status_handle: Option<SpawnHandle>,
+    status_duration: Duration,
+    statuses: HashMap<Uuid, TransferStatus>,
+    expiry: ExpiryQueue<Expiring>,
+    store: Store,
+    devices: Devices,
+    metrics: Metrics,
 }
 
 impl Cleanup {
-    pub fn new(transfers: Transfers) -> Self {
+    pub fn new(
+        transfers: Transfers,
+        status_duration: Duration,
+        store: Store,
+        devices: Devices,
+    ) -> Self {
         Self {
             transfers,
+            status_duration,
             statuses: HashMap::new(),
-            pending: Vec::new(),
-            pending_handle: None,
-            status_handle: None,
+            expiry: ExpiryQueue::new(),
+            store,
+            devices,
+            metrics: Metrics::default(),
         }
     }
 
-    fn process_pending(&mut self, ctx: &mut Context<Self>) {
-        debug!("Processing pending transfers");
-        let mut transfers = self.transfers.0.always_lock();
-        let now = Instant::now();
-        self.pending.retain(|(id, start)| {
-            if now - *start > TRANSFER_DURATION {
-                transfers.remove(id);
-                false
-            } else {
-                true
+    /// Restores the transfers and statuses that weren't expired before the last shutdown.
+    pub fn restore(&mut self, quotas: &Quotas) {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let deadline = |expires_at: i64| {
+            Instant::now() + Duration::from_secs(expires_at.saturating_sub(now).max(0) as u64)
+        };
+
+        let mut restored_transfers = 0;
+        for (id, transfer) in self.store.transfers() {
+            let content_type = match transfer.content_type.parse() {
+                Ok(ct) if transfer.expires_at > now => ct,
+                Ok(_) => {
+                    self.store.remove_transfer(id);
+                    continue;
+                }
+                Err(e) => {
+                    warn!(%id, error = %e, "Dropping stored transfer with a bad content type");
+                    self.store.remove_transfer(id);
+                    continue;
+                }
+            };
+            let record = TransferRecord {
+                user: transfer.user,
+                filename: transfer.filename,
+                created_at: OffsetDateTime::from_unix_timestamp(transfer.created_at)
+                    .unwrap_or_else(|_| OffsetDateTime::now_utc()),
+                receiver: None,
+                digest: transfer.digest,
+                preview: transfer.preview,
+                declared_type: transfer.declared_type.and_then(|t| t.parse().ok()),
+                detected_type: None,
+                thumbnail: None,
+                device: transfer.device,
+            };
+            let permit = quotas.acquire_unchecked(&record.user);
+            self.transfers
+                .insert_transfer(id, record, content_type, transfer.limits, permit);
+            self.expiry
+                .insert(Expiring::Transfer(id), deadline(transfer.expires_at));
+            restored_transfers += 1;
+        }
+
+        let mut restored_statuses = 0;
+        for (id, StoredStatus { status, expires_at }) in self.store.statuses() {
+            if expires_at <= now {
+                self.store.remove_status(id);
+                continue;
             }
-        });
-        self.pending_handle = if self.pending.is_empty() {
-            None
-        } else {
-            let next_check = self
-                .pending
-                .iter()
-                .min_by_key(|t| t.1)
-                .map(|t| t.1)
-                .unwrap_or_else(Instant::now)
-                .saturating_duration_since(Instant::now())
-                .max(Duration::from_secs(1));
-            dbg!(&self.pending);
-            dbg!(&next_check);
-            Some(ctx.run_later(next_check, Self::process_pending))
+            self.statuses.insert(id, status);
+            self.expiry
+                .insert(Expiring::Status(id), deadline(expires_at));
+            restored_statuses += 1;
         }
+
+        info!(
+            transfers = restored_transfers,
+            statuses = restored_statuses,
+            "Restored state"
+        );
     }
 
-    fn process_statuses(&mut self, ctx: &mut Context<Self>) {
-        debug!("Processing pending statuses");
-        let now = Instant::now();
-        self.statuses
-            .retain(|_, (start, _)| now - *start < STATUS_DURATION);
-
-        self.status_handle = if self.statuses.is_empty() {
-            None
-        } else {
-            let next_check = self
-                .statuses
-                .iter()
-                .min_by_key(|(_, (i, _))| i)
-                .map(|(_, (i, _))| *i)
-                .unwrap_or_else(Instant::now)
-                .saturating_duration_since(Instant::now())
-                .min(Duration::from_secs(1));
-            Some(ctx.run_later(next_check, Self::process_statuses))
+    fn expire(&mut self, item: Expiring) {
+        debug!(?item, "Expired");
+        match item {
+            Expiring::Transfer(id) => {
+                // a transfer that's sending already is up to the sender's request
+                let info = self.transfers.remove_pending(&id);
+                self.store.remove_transfer(id);
+                if let Some(record) = info.as_ref().and_then(TransferInfo::record) {
+                    let status = TransferStatus::failed(FailureReason::Expired);
+                    self.metrics.finished(&status);
+                    self.store
+                        .put_history(&record.user, &record.finish(id, 0, status));
+                }
+            }
+            Expiring::Status(id) => {
+                self.statuses.remove(&id);
+                self.store.remove_status(id);
+            }
+            Expiring::Pairing(code) => self.devices.expire_pairing(&code),
+            Expiring::Spool(path) => match std::fs::remove_file(&path) {
+                // quarantined payloads were moved already
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    warn!(path = %path.display(), error = %e, "Couldn't remove a spooled payload");
+                    self.expiry
+                        .insert(Expiring::Spool(path), Instant::now() + SPOOL_RETRY);
+                }
+                _ => (),
+            },
         }
     }
 }
 
+struct ExpireDriver;
+
+impl ActorFuture<Cleanup> for ExpireDriver {
+    type Output = ();
+
+    fn poll(
+        self: Pin<&mut Self>,
+        act: &mut Cleanup,
+        _: &mut Context<Cleanup>,
+        task: &mut task::Context<'_>,
+    ) -> Poll<Self::Output> {
+        while let Poll::Ready(item) = act.expiry.poll_expired(task) {
+            act.expire(item);
+        }
+        Poll::Pending
+    }
+}
+
+#[derive(Message)]
+#[rtype("()")]
+pub struct TrackTransfer(pub Uuid, pub Instant);
+
 #[derive(Message)]
 #[rtype("()")]
-pub struct TrackTransfer(pub Uuid);
+pub struct TrackPairing(pub String, pub Instant);
 
+/// Tried again later if removing the file fails.
 #[derive(Message)]
 #[rtype("()")]
-pub struct PutStatus(pub Uuid, pub bool);
+pub struct RemoveSpool(pub PathBuf);
 
 #[derive(Message)]
-#[rtype("Option<bool>")]
+#[rtype("()")]
+pub struct PutStatus(pub Uuid, pub TransferStatus);
+
+#[derive(Message)]
+#[rtype("Option<TransferStatus>")]
 pub struct GetStatus(pub Uuid);
 
 impl Actor for Cleanup {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.spawn(ExpireDriver);
+    }
 }
 
 impl Handler<TrackTransfer> for Cleanup {
@@ -102,28 +206,65 @@ impl Handler<TrackTransfer> for Cleanup {
 
     fn handle(
         &mut self,
-        TrackTransfer(id): TrackTransfer,
-        ctx: &mut Self::Context,
+        TrackTransfer(id, deadline): TrackTransfer,
+        _: &mut Self::Context,
     ) -> Self::Result {
-        if self.pending_handle.is_none() {
-            self.pending_handle = Some(ctx.run_later(TRANSFER_DURATION, Self::process_pending));
-        }
-        self.pending.push((id, Instant::now()));
+        self.metrics.created += 1;
+        self.expiry.insert(Expiring::Transfer(id), deadline);
     }
 }
 
-impl Handler<PutStatus> for Cleanup {
+impl Handler<TrackPairing> for Cleanup {
     type Result = ();
 
     fn handle(
         &mut self,
-        PutStatus(id, status): PutStatus,
-        ctx: &mut Self::Context,
+        TrackPairing(code, deadline): TrackPairing,
+        _: &mut Self::Context,
     ) -> Self::Result {
-        if self.pending_handle.is_none() {
-            self.pending_handle = Some(ctx.run_later(STATUS_DURATION, Self::process_statuses));
-        }
-        self.statuses.insert(id, (Instant::now(), status));
+        self.expiry.insert(Expiring::Pairing(code), deadline);
+    }
+}
+
+impl Handler<RemoveSpool> for Cleanup {
+    type Result = ();
+
+    fn handle(&mut self, RemoveSpool(path): RemoveSpool, _: &mut Self::Context) -> Self::Result {
+        self.expiry.insert(Expiring::Spool(path), Instant::now());
+    }
+}
+
+impl Handler<PutStatus> for Cleanup {
+    type Result = ();
+
+    fn handle(&mut self, PutStatus(id, status): PutStatus, _: &mut Self::Context) -> Self::Result {
+        // the transfer is finished, so it doesn't need to expire anymore
+        self.expiry.remove(&Expiring::Transfer(id));
+        self.transfers.finish(&id);
+        self.store.remove_transfer(id);
+        self.metrics.finished(&status);
+        self.expiry
+            .insert(Expiring::Status(id), Instant::now() + self.status_duration);
+        self.store.put_status(
+            id,
+            &StoredStatus {
+                status: status.clone(),
+                expires_at: (OffsetDateTime::now_utc() + self.status_duration).unix_timestamp(),
+            },
+        );
+        self.statuses.insert(id, status);
+    }
+}
+
+#[derive(Message)]
+#[rtype("String")]
+pub struct GetMetrics;
+
+impl Handler<GetMetrics> for Cleanup {
+    type Result = MessageResult<GetMetrics>;
+
+    fn handle(&mut self, _: GetMetrics, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.metrics.render(self.transfers.count()))
     }
 }
 
@@ -131,6 +272,231 @@ impl Handler<GetStatus> for Cleanup {
     type Result = MessageResult<GetStatus>;
 
     fn handle(&mut self, GetStatus(id): GetStatus, _: &mut Self::Context) -> Self::Result {
-        MessageResult(self.statuses.get(&id).map(|(_, s)| *s))
+        MessageResult(self.statuses.get(&id).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        quota::{QuotaConfig, Quotas},
+        store::StoredTransfer,
+        upload::UploadLimits,
+    };
+
+    fn new_transfer(transfers: &Transfers) -> Uuid {
+        let quotas = Quotas::new(QuotaConfig::default());
+        let id = Uuid::new_v4();
+        transfers.insert_transfer(
+            id,
+            TransferRecord::new("test".to_owned(), "file.txt".to_owned(), None),
+            mime::TEXT_PLAIN,
+            UploadLimits::default(),
+            quotas.acquire("test").unwrap(),
+        );
+        id
+    }
+
+    fn start(transfers: &Transfers) -> actix::Addr<Cleanup> {
+        Cleanup::new(
+            transfers.clone(),
+            Duration::from_secs(60),
+            Store::disabled(),
+            Devices::new(Store::disabled()),
+        )
+        .start()
+    }
+
+    async fn advance(cleanup: &actix::Addr<Cleanup>, secs: u64) {
+        tokio::time::advance(Duration::from_secs(secs)).await;
+        cleanup.send(GetStatus(Uuid::nil())).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn removes_transfers_after_deadline() {
+        tokio::time::pause();
+        let transfers = Transfers::new();
+        let cleanup = start(&transfers);
+        let id = new_transfer(&transfers);
+        cleanup
+            .send(TrackTransfer(id, Instant::now() + Duration::from_secs(600)))
+            .await
+            .unwrap();
+
+        advance(&cleanup, 599).await;
+        assert!(transfers.receiver_rx(&id).is_some());
+
+        advance(&cleanup, 2).await;
+        assert!(transfers.receiver_rx(&id).is_none());
+    }
+
+    #[actix_web::test]
+    async fn finished_transfers_are_removed() {
+        tokio::time::pause();
+        let transfers = Transfers::new();
+        let cleanup = start(&transfers);
+        let id = new_transfer(&transfers);
+        cleanup
+            .send(TrackTransfer(id, Instant::now() + Duration::from_secs(10)))
+            .await
+            .unwrap();
+        cleanup
+            .send(PutStatus(
+                id,
+                TransferStatus::Completed {
+                    digest: None,
+                    scan: None,
+                },
+            ))
+            .await
+            .unwrap();
+        assert!(transfers.receiver_rx(&id).is_none());
+
+        // the deadline doesn't replace the status
+        advance(&cleanup, 11).await;
+        assert_eq!(
+            cleanup.send(GetStatus(id)).await.unwrap(),
+            Some(TransferStatus::Completed {
+                digest: None,
+                scan: None
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn unused_pairing_codes_expire() {
+        tokio::time::pause();
+        let devices = Devices::new(Store::disabled());
+        let cleanup = Cleanup::new(
+            Transfers::new(),
+            Duration::from_secs(60),
+            Store::disabled(),
+            devices.clone(),
+        )
+        .start();
+        let pairing = devices.start_pairing("alice", "Phone");
+        cleanup
+            .send(TrackPairing(pairing.code, pairing.deadline))
+            .await
+            .unwrap();
+
+        advance(&cleanup, 599).await;
+        assert_eq!(devices.pending_pairings(), 1);
+        advance(&cleanup, 2).await;
+        assert_eq!(devices.pending_pairings(), 0);
+    }
+
+    #[actix_web::test]
+    async fn statuses_expire() {
+        tokio::time::pause();
+        let transfers = Transfers::new();
+        let cleanup = start(&transfers);
+        let id = Uuid::new_v4();
+        let status = TransferStatus::failed(FailureReason::IdleTimeout);
+        cleanup.send(PutStatus(id, status.clone())).await.unwrap();
+
+        advance(&cleanup, 59).await;
+        assert_eq!(cleanup.send(GetStatus(id)).await.unwrap(), Some(status));
+
+        advance(&cleanup, 2).await;
+        assert_eq!(cleanup.send(GetStatus(id)).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn putting_a_status_again_extends_it() {
+        tokio::time::pause();
+        let transfers = Transfers::new();
+        let cleanup = start(&transfers);
+        let id = Uuid::new_v4();
+        cleanup
+            .send(PutStatus(
+                id,
+                TransferStatus::Completed {
+                    digest: None,
+                    scan: None,
+                },
+            ))
+            .await
+            .unwrap();
+
+        advance(&cleanup, 40).await;
+        cleanup
+            .send(PutStatus(
+                id,
+                TransferStatus::Completed {
+                    digest: None,
+                    scan: None,
+                },
+            ))
+            .await
+            .unwrap();
+        advance(&cleanup, 40).await;
+        assert_eq!(
+            cleanup.send(GetStatus(id)).await.unwrap(),
+            Some(TransferStatus::Completed {
+                digest: None,
+                scan: None
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn restores_unexpired_transfers_and_statuses() {
+        tokio::time::pause();
+        let store = Store::in_memory();
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let stored = |expires_at| StoredTransfer {
+            filename: "file.txt".to_owned(),
+            content_type: "text/plain".to_owned(),
+            user: "test".to_owned(),
+            limits: UploadLimits::default(),
+            digest: None,
+            preview: false,
+            declared_type: None,
+            device: None,
+            created_at: now,
+            expires_at,
+        };
+        let (pending, expired, finished) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        store.put_transfer(pending, &stored(now + 30));
+        store.put_transfer(expired, &stored(now - 1));
+        store.put_status(
+            finished,
+            &StoredStatus {
+                status: TransferStatus::Completed {
+                    digest: None,
+                    scan: None,
+                },
+                expires_at: now + 30,
+            },
+        );
+
+        let transfers = Transfers::new();
+        let mut cleanup = Cleanup::new(
+            transfers.clone(),
+            Duration::from_secs(60),
+            store.clone(),
+            Devices::new(store.clone()),
+        );
+        cleanup.restore(&Quotas::new(QuotaConfig::default()));
+        let cleanup = cleanup.start();
+
+        assert!(transfers.receiver_rx(&pending).is_some());
+        assert!(transfers.receiver_rx(&expired).is_none());
+        assert_eq!(store.transfers().len(), 1);
+        assert_eq!(
+            cleanup.send(GetStatus(finished)).await.unwrap(),
+            Some(TransferStatus::Completed {
+                digest: None,
+                scan: None
+            })
+        );
+
+        advance(&cleanup, 31).await;
+        assert!(transfers.receiver_rx(&pending).is_none());
+        assert_eq!(cleanup.send(GetStatus(finished)).await.unwrap(), None);
+        assert!(store.transfers().is_empty());
+        assert!(store.statuses().is_empty());
     }
 }
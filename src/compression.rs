@@ -0,0 +1,255 @@
+use actix_http::encoding::Encoder;
+use actix_web::{
+    body::{BodyStream, MessageBody},
+    dev::Decompress,
+    error::PayloadError,
+    http::header::{self, AcceptEncoding, ContentEncoding, Encoding},
+    HttpResponse, HttpResponseBuilder,
+};
+use bytes::Bytes;
+use futures::Stream;
+use serde::Deserialize;
+use std::io;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_encodings")]
+    pub encodings: Vec<Codec>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+fn default_encodings() -> Vec<Codec> {
+    vec![Codec::Zstd, Codec::Br, Codec::Gzip]
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            encodings: default_encodings(),
+        }
+    }
+}
+
+impl From<Codec> for ContentEncoding {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::Gzip => ContentEncoding::Gzip,
+            Codec::Br => ContentEncoding::Brotli,
+            Codec::Zstd => ContentEncoding::Zstd,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// Always contains `identity`, empty if compression is disabled.
+    supported: Vec<Encoding>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Unchanged(ContentEncoding),
+    /// The sender's encoding is removed and `encode` is applied instead.
+    Recoded {
+        decode: ContentEncoding,
+        encode: ContentEncoding,
+    },
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Self {
+        let supported = match config.enabled {
+            true => config
+                .encodings
+                .iter()
+                .map(|&codec| Encoding::Known(codec.into()))
+                .chain([Encoding::identity()])
+                .collect(),
+            false => Vec::new(),
+        };
+        Self { supported }
+    }
+
+    pub fn negotiate(
+        &self,
+        accept: &AcceptEncoding,
+        content_type: &mime::Mime,
+        uploaded: ContentEncoding,
+    ) -> Coding {
+        let known = |enc: ContentEncoding| Some(Encoding::Known(enc));
+        if uploaded != ContentEncoding::Identity
+            && accept.negotiate([Encoding::Known(uploaded)].iter()) == known(uploaded)
+        {
+            return Coding::Unchanged(uploaded);
+        }
+
+        let encode = match accept.negotiate(self.supported.iter()) {
+            Some(Encoding::Known(enc)) if is_compressible(content_type) => enc,
+            _ => ContentEncoding::Identity,
+        };
+        match (uploaded, encode) {
+            (ContentEncoding::Identity, ContentEncoding::Identity) => {
+                Coding::Unchanged(ContentEncoding::Identity)
+            }
+            (decode, encode) => Coding::Recoded { decode, encode },
+        }
+    }
+}
+
+impl Coding {
+    pub fn respond<B>(self, mut res: HttpResponseBuilder, body: B) -> HttpResponse
+    where
+        B: MessageBody + 'static,
+    {
+        match self {
+            Coding::Unchanged(ContentEncoding::Identity) => res.body(body),
+            Coding::Unchanged(encoding) => res
+                .insert_header((header::CONTENT_ENCODING, encoding))
+                .body(body),
+            Coding::Recoded { decode, encode } => {
+                let body = BodyStream::new(Decompress::new(into_stream(body), decode));
+                res.body(body)
+                    .map_body(|head, body| Encoder::response(encode, head, body))
+                    .map_into_boxed_body()
+            }
+        }
+    }
+}
+
+/// Whether compressing `mime` is worth it, most media and archive formats are compressed already.
+pub fn is_compressible(mime: &mime::Mime) -> bool {
+    match (mime.type_(), mime.subtype().as_str()) {
+        (mime::IMAGE, sub) => sub == "svg" || sub == "bmp" || sub == "x-ms-bmp",
+        (mime::VIDEO | mime::AUDIO | mime::FONT, _) => false,
+        (mime::APPLICATION, sub) => {
+            !matches!(
+                sub,
+                "zip"
+                    | "gzip"
+                    | "x-gzip"
+                    | "zstd"
+                    | "x-bzip2"
+                    | "x-xz"
+                    | "x-7z-compressed"
+                    | "vnd.rar"
+                    | "x-rar-compressed"
+                    | "java-archive"
+                    | "vnd.android.package-archive"
+                    | "epub+zip"
+                    | "pdf"
+            ) && !sub.starts_with("vnd.openxmlformats-officedocument")
+        }
+        _ => true,
+    }
+}
+
+fn into_stream<B>(body: B) -> impl Stream<Item = Result<Bytes, PayloadError>>
+where
+    B: MessageBody,
+{
+    let mut body = Box::pin(body);
+    futures::stream::poll_fn(move |cx| {
+        body.as_mut().poll_next(cx).map(|chunk| {
+            chunk.map(|res| {
+                res.map_err(|e| PayloadError::Io(io::Error::other(e.into().to_string())))
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(header: &str) -> AcceptEncoding {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::ACCEPT_ENCODING, header))
+            .to_http_request();
+        actix_web::HttpMessage::get_header(&req).unwrap()
+    }
+
+    fn enabled() -> Compression {
+        Compression::new(&CompressionConfig {
+            enabled: true,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn compresses_text_for_accepting_receivers() {
+        assert_eq!(
+            enabled().negotiate(
+                &accept("gzip"),
+                &mime::TEXT_PLAIN,
+                ContentEncoding::Identity
+            ),
+            Coding::Recoded {
+                decode: ContentEncoding::Identity,
+                encode: ContentEncoding::Gzip
+            }
+        );
+        assert_eq!(
+            enabled().negotiate(
+                &accept("identity"),
+                &mime::TEXT_PLAIN,
+                ContentEncoding::Identity
+            ),
+            Coding::Unchanged(ContentEncoding::Identity)
+        );
+    }
+
+    #[test]
+    fn skips_compressed_formats_and_disabled_compression() {
+        let zip = "application/zip".parse().unwrap();
+        assert_eq!(
+            enabled().negotiate(&accept("gzip"), &zip, ContentEncoding::Identity),
+            Coding::Unchanged(ContentEncoding::Identity)
+        );
+        assert_eq!(
+            Compression::new(&CompressionConfig::default()).negotiate(
+                &accept("gzip"),
+                &mime::TEXT_PLAIN,
+                ContentEncoding::Identity
+            ),
+            Coding::Unchanged(ContentEncoding::Identity)
+        );
+    }
+
+    #[test]
+    fn passes_through_or_decodes_sender_encodings() {
+        let disabled = Compression::new(&CompressionConfig::default());
+        assert_eq!(
+            disabled.negotiate(
+                &accept("gzip, br"),
+                &mime::TEXT_PLAIN,
+                ContentEncoding::Brotli
+            ),
+            Coding::Unchanged(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            disabled.negotiate(&accept("gzip"), &mime::TEXT_PLAIN, ContentEncoding::Brotli),
+            Coding::Recoded {
+                decode: ContentEncoding::Brotli,
+                encode: ContentEncoding::Identity
+            }
+        );
+        assert_eq!(
+            enabled().negotiate(&accept("gzip"), &mime::TEXT_PLAIN, ContentEncoding::Brotli),
+            Coding::Recoded {
+                decode: ContentEncoding::Brotli,
+                encode: ContentEncoding::Gzip
+            }
+        );
+    }
+}
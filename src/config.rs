@@ -1,18 +1,130 @@
-use std::fs;
+use crate::{
+    assets::AssetsConfig,
+    bind::{self, BindAddress},
+    compression::CompressionConfig,
+    images::ImageConfig,
+    lifetime::LifetimeConfig,
+    mdns::MdnsConfig,
+    proxy::ProxyConfig,
+    quota::QuotaConfig,
+    scan::ScanConfig,
+    sniff::ContentTypeConfig,
+    upload::UploadLimits,
+};
 use serde::Deserialize;
+use std::{collections::HashMap, fs, num::NonZeroU64, path::PathBuf, time::Duration};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
-    pub bind: String,
+    /// One or more addresses, see [`bind::deserialize`].
+    #[serde(deserialize_with = "bind::deserialize")]
+    pub bind: Vec<BindAddress>,
+    /// Where the admin listener with `/metrics` binds, like `bind`. It's not started if unset.
+    #[serde(default, deserialize_with = "bind::deserialize")]
+    pub admin_bind: Vec<BindAddress>,
     pub token: String,
+    /// Additional users with their own token (name -> token).
+    #[serde(default)]
+    pub users: HashMap<String, String>,
     pub jwt_secret: String,
     #[serde(default)]
     pub log_filter: Option<String>,
+    /// Where to persist transfers and statuses, nothing is persisted if unset.
+    #[serde(default)]
+    pub database: Option<PathBuf>,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub lifetime: LifetimeConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub content_type: ContentTypeConfig,
+    #[serde(default)]
+    pub images: ImageConfig,
+    #[serde(default)]
+    pub scan: ScanConfig,
+    #[serde(default)]
+    pub mdns: MdnsConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub assets: AssetsConfig,
+}
+
+impl Config {
+    /// Maps every accepted token to its user.
+    /// The `token` belongs to the user `default`.
+    pub fn tokens(&self) -> HashMap<String, String> {
+        self.users
+            .iter()
+            .map(|(user, token)| (token.clone(), user.clone()))
+            .chain(std::iter::once((self.token.clone(), "default".to_owned())))
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UploadConfig {
+    /// In seconds.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: NonZeroU64,
+    /// In seconds.
+    #[serde(default)]
+    pub max_duration: Option<NonZeroU64>,
+    /// In bytes per second.
+    #[serde(default)]
+    pub min_throughput: Option<u64>,
+}
+
+fn default_idle_timeout() -> NonZeroU64 {
+    NonZeroU64::new(UploadLimits::default().idle_timeout.as_secs()).unwrap()
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: default_idle_timeout(),
+            max_duration: None,
+            min_throughput: None,
+        }
+    }
+}
+
+impl UploadConfig {
+    pub fn limits(&self) -> UploadLimits {
+        UploadLimits {
+            idle_timeout: Duration::from_secs(self.idle_timeout.get()),
+            max_duration: self.max_duration.map(|t| Duration::from_secs(t.get())),
+            min_throughput: self.min_throughput,
+        }
+    }
 }
 
 pub fn parse_config() -> Config {
-    let file = fs::read("config.toml")
-        .expect("Couldn't open config.toml");
+    let file = fs::read("config.toml").expect("Couldn't open config.toml");
     toml::from_slice(&file).expect("Couldn't parse config")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(toml: &str) -> Result<UploadConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn rejects_zero_upload_timeouts() {
+        let config = upload("idle-timeout = 30\nmax-duration = 600").unwrap();
+        assert_eq!(config.limits().idle_timeout, Duration::from_secs(30));
+        assert_eq!(config.limits().max_duration, Some(Duration::from_secs(600)));
+
+        assert!(upload("idle-timeout = 0").is_err());
+        assert!(upload("max-duration = 0").is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use crate::{
+    history::TransferRecord,
+    mutex::MutexExt,
+    openapi,
+    store::{Store, StoredDevice},
+};
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+use time::OffsetDateTime;
+use tokio::{sync::broadcast, time::Instant};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub const DEVICE_COOKIE: &str = "frachter-device";
+/// Clients that can't keep cookies send their device token in this header.
+pub const DEVICE_HEADER: &str = "x-frachter-device";
+const PAIRING_LIFETIME: Duration = Duration::from_secs(10 * 60);
+pub const MAX_NAME_LEN: usize = 64;
+
+/// A device that's paired with a user and receives the transfers sent to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user: String,
+    pub name: String,
+    /// Unix timestamp in seconds.
+    pub paired_at: i64,
+}
+
+/// A transfer waiting for a device, sent to the device's inbox.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Offer {
+    #[serde(skip)]
+    pub device: Uuid,
+    pub id: Uuid,
+    pub filename: String,
+    #[serde(with = "crate::serde_util::mime")]
+    #[schema(value_type = String)]
+    pub content_type: mime::Mime,
+    pub preview: bool,
+    /// The user that sent the transfer.
+    pub user: String,
+}
+
+/// A one-time code that pairs a device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Pairing {
+    pub code: String,
+    /// Unix timestamp in seconds.
+    pub expires_at: i64,
+    /// When the code has to be passed to [`Devices::expire_pairing`].
+    #[serde(skip)]
+    pub deadline: Instant,
+}
+
+struct PendingPairing {
+    user: String,
+    name: String,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// Devices with the SHA-256 of their token.
+    devices: HashMap<Uuid, (Device, Vec<u8>)>,
+    pairings: HashMap<String, PendingPairing>,
+}
+
+#[derive(Clone)]
+pub struct Devices {
+    state: Arc<StdMutex<State>>,
+    offers: broadcast::Sender<Offer>,
+    store: Store,
+}
+
+impl Offer {
+    pub fn new(device: Uuid, id: Uuid, record: &TransferRecord, content_type: &mime::Mime) -> Self {
+        Self {
+            device,
+            id,
+            filename: record.filename.clone(),
+            content_type: content_type.clone(),
+            preview: record.preview,
+            user: record.user.clone(),
+        }
+    }
+}
+
+impl Devices {
+    pub fn new(store: Store) -> Self {
+        let devices = store
+            .devices()
+            .into_iter()
+            .filter_map(|(id, stored)| {
+                let token_hash = URL_SAFE_NO_PAD.decode(&stored.token_hash).ok()?;
+                let device = Device {
+                    id,
+                    user: stored.user,
+                    name: stored.name,
+                    paired_at: stored.paired_at,
+                };
+                Some((id, (device, token_hash)))
+            })
+            .collect();
+        let (offers, _) = broadcast::channel(64);
+
+        Self {
+            state: Arc::new(StdMutex::new(State {
+                devices,
+                pairings: HashMap::new(),
+            })),
+            offers,
+            store,
+        }
+    }
+
+    pub fn start_pairing(&self, user: &str, name: &str) -> Pairing {
+        let code = random_token(16);
+        let deadline = Instant::now() + PAIRING_LIFETIME;
+        self.state.always_lock().pairings.insert(
+            code.clone(),
+            PendingPairing {
+                user: user.to_owned(),
+                name: name.to_owned(),
+                expires: deadline,
+            },
+        );
+
+        Pairing {
+            code,
+            expires_at: (OffsetDateTime::now_utc() + PAIRING_LIFETIME).unix_timestamp(),
+            deadline,
+        }
+    }
+
+    pub fn expire_pairing(&self, code: &str) {
+        self.state.always_lock().pairings.remove(code);
+    }
+
+    #[cfg(test)]
+    pub fn pending_pairings(&self) -> usize {
+        self.state.always_lock().pairings.len()
+    }
+
+    /// Returns the device and its token, every code can only be used once.
+    pub fn pair(&self, code: &str) -> Option<(Device, String)> {
+        let mut state = self.state.always_lock();
+        let pairing = state
+            .pairings
+            .remove(code)
+            .filter(|p| p.expires > Instant::now())?;
+        let token = random_token(32);
+        let token_hash = hash(&token);
+        let device = Device {
+            id: Uuid::new_v4(),
+            user: pairing.user,
+            name: pairing.name,
+            paired_at: OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        self.store.put_device(
+            device.id,
+            &StoredDevice {
+                user: device.user.clone(),
+                name: device.name.clone(),
+                paired_at: device.paired_at,
+                token_hash: URL_SAFE_NO_PAD.encode(&token_hash),
+            },
+        );
+        state
+            .devices
+            .insert(device.id, (device.clone(), token_hash));
+
+        Some((device, token))
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Device> {
+        let token_hash = hash(token);
+        self.state
+            .always_lock()
+            .devices
+            .values()
+            .find(|(_, h)| *h == token_hash)
+            .map(|(device, _)| device.clone())
+    }
+
+    pub fn belongs_to(&self, id: &Uuid, user: &str) -> bool {
+        self.state
+            .always_lock()
+            .devices
+            .get(id)
+            .is_some_and(|(device, _)| device.user == user)
+    }
+
+    /// In the order they were paired.
+    pub fn list(&self, user: &str) -> Vec<Device> {
+        let mut devices: Vec<_> = self
+            .state
+            .always_lock()
+            .devices
+            .values()
+            .filter(|(device, _)| device.user == user)
+            .map(|(device, _)| device.clone())
+            .collect();
+        devices.sort_by_key(|d| (d.paired_at, d.id));
+        devices
+    }
+
+    pub fn remove(&self, user: &str, id: &Uuid) -> bool {
+        let mut state = self.state.always_lock();
+        match state.devices.get(id) {
+            Some((device, _)) if device.user == user => (),
+            _ => return false,
+        }
+        state.devices.remove(id);
+        self.store.remove_device(*id);
+        true
+    }
+
+    pub fn offer(&self, offer: Offer) {
+        // nobody might be listening
+        self.offers.send(offer).ok();
+    }
+
+    /// Offers to every device, inboxes filter out the ones for their device.
+    pub fn subscribe(&self) -> broadcast::Receiver<Offer> {
+        self.offers.subscribe()
+    }
+}
+
+fn random_token(bytes: usize) -> String {
+    let mut token = vec![0; bytes];
+    rand::thread_rng().fill_bytes(&mut token);
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+/// Only hashes of tokens are kept, so the database doesn't contain them.
+fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// The paired device that made the request, authenticated by its cookie or header.
+pub struct PairedDevice(pub Device);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+pub enum PairedDeviceError {
+    #[error("Missing device token")]
+    #[status(401)]
+    NoToken,
+    #[error("This device isn't paired (anymore)")]
+    #[status(401)]
+    BadToken,
+    #[error("No info about devices")]
+    #[status(500)]
+    NoRequestInfo,
+}
+
+openapi::error_responses!(
+    PairedDeviceError,
+    [
+        PairedDeviceError::NoToken,
+        PairedDeviceError::BadToken,
+        PairedDeviceError::NoRequestInfo,
+    ]
+);
+
+impl FromRequest for PairedDevice {
+    type Error = PairedDeviceError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let Some(devices) = req.app_data::<web::Data<Devices>>() else {
+            return ready(Err(PairedDeviceError::NoRequestInfo));
+        };
+        let token = match req.headers().get(DEVICE_HEADER) {
+            Some(h) => h.to_str().ok().map(str::to_owned),
+            None => req.cookie(DEVICE_COOKIE).map(|c| c.value().to_owned()),
+        };
+        ready(match token {
+            Some(token) => devices
+                .authenticate(&token)
+                .map(Self)
+                .ok_or(PairedDeviceError::BadToken),
+            None => Err(PairedDeviceError::NoToken),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn pairing_codes_are_one_time() {
+        let devices = Devices::new(Store::disabled());
+        let pairing = devices.start_pairing("alice", "Phone");
+        let (device, token) = devices.pair(&pairing.code).unwrap();
+        assert_eq!(
+            (device.user.as_str(), device.name.as_str()),
+            ("alice", "Phone")
+        );
+        assert!(devices.pair(&pairing.code).is_none());
+        assert_eq!(devices.authenticate(&token), Some(device));
+        assert!(devices.authenticate("not a token").is_none());
+
+        let pairing = devices.start_pairing("alice", "Laptop");
+        tokio::time::advance(PAIRING_LIFETIME).await;
+        assert!(devices.pair(&pairing.code).is_none());
+    }
+
+    #[test]
+    fn devices_belong_to_their_user() {
+        let devices = Devices::new(Store::disabled());
+        let (device, token) = devices
+            .pair(&devices.start_pairing("alice", "Phone").code)
+            .unwrap();
+        assert!(devices.belongs_to(&device.id, "alice"));
+        assert!(!devices.belongs_to(&device.id, "bob"));
+        assert!(devices.list("bob").is_empty());
+
+        assert!(!devices.remove("bob", &device.id));
+        assert!(devices.remove("alice", &device.id));
+        assert!(devices.list("alice").is_empty());
+        assert!(devices.authenticate(&token).is_none());
+    }
+
+    #[test]
+    fn devices_are_restored() {
+        let store = Store::in_memory();
+        let devices = Devices::new(store.clone());
+        let (device, token) = devices
+            .pair(&devices.start_pairing("alice", "Phone").code)
+            .unwrap();
+
+        let restored = Devices::new(store);
+        assert_eq!(restored.list("alice"), vec![device.clone()]);
+        assert_eq!(restored.authenticate(&token), Some(device));
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    task::{Context, Poll},
+};
+use tokio::time::Instant;
+use tokio_util::time::{delay_queue, DelayQueue};
+
+/// Every key is in the queue at most once, inserting it again reschedules it.
+pub struct ExpiryQueue<K> {
+    queue: DelayQueue<K>,
+    keys: HashMap<K, delay_queue::Key>,
+}
+
+impl<K: Hash + Eq + Clone> ExpiryQueue<K> {
+    pub fn new() -> Self {
+        Self {
+            queue: DelayQueue::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, deadline: Instant) {
+        match self.keys.get(&key) {
+            Some(queue_key) => self.queue.reset_at(queue_key, deadline),
+            None => {
+                let queue_key = self.queue.insert_at(key.clone(), deadline);
+                self.keys.insert(key, queue_key);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool {
+        match self.keys.remove(key) {
+            Some(queue_key) => {
+                self.queue.remove(&queue_key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Unlike [`DelayQueue`], this stays pending while the queue is empty and is woken once a key
+    /// is inserted.
+    pub fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<K> {
+        match self.queue.poll_expired(cx) {
+            Poll::Ready(Some(expired)) => {
+                let key = expired.into_inner();
+                self.keys.remove(&key);
+                Poll::Ready(key)
+            }
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future::poll_fn, FutureExt};
+    use std::time::Duration;
+
+    async fn next<K: Hash + Eq + Clone>(queue: &mut ExpiryQueue<K>) -> K {
+        poll_fn(|cx| queue.poll_expired(cx)).await
+    }
+
+    fn now_ready<K: Hash + Eq + Clone>(queue: &mut ExpiryQueue<K>) -> Option<K> {
+        poll_fn(|cx| queue.poll_expired(cx)).now_or_never()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_in_deadline_order() {
+        let mut queue = ExpiryQueue::new();
+        let now = Instant::now();
+        queue.insert("b", now + Duration::from_secs(20));
+        queue.insert("a", now + Duration::from_secs(10));
+
+        assert_eq!(next(&mut queue).await, "a");
+        assert_eq!(Instant::now() - now, Duration::from_secs(10));
+        assert_eq!(next(&mut queue).await, "b");
+        assert_eq!(Instant::now() - now, Duration::from_secs(20));
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removed_keys_never_expire() {
+        let mut queue = ExpiryQueue::new();
+        let now = Instant::now();
+        queue.insert("a", now + Duration::from_secs(10));
+        queue.insert("b", now + Duration::from_secs(20));
+
+        assert!(queue.remove(&"a"));
+        assert!(!queue.remove(&"a"));
+        assert_eq!(next(&mut queue).await, "b");
+        assert_eq!(Instant::now() - now, Duration::from_secs(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn inserting_again_reschedules() {
+        let mut queue = ExpiryQueue::new();
+        let now = Instant::now();
+        queue.insert("a", now + Duration::from_secs(10));
+        queue.insert("a", now + Duration::from_secs(30));
+        assert_eq!(queue.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(15)).await;
+        assert_eq!(now_ready(&mut queue), None);
+        assert_eq!(next(&mut queue).await, "a");
+        assert_eq!(Instant::now() - now, Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stays_pending_while_empty() {
+        let mut queue = ExpiryQueue::<&str>::new();
+        assert_eq!(now_ready(&mut queue), None);
+
+        queue.insert("a", Instant::now() + Duration::from_secs(1));
+        assert_eq!(next(&mut queue).await, "a");
+        assert_eq!(now_ready(&mut queue), None);
+    }
+}
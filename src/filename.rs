@@ -0,0 +1,169 @@
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+
+/// The longest filename (in bytes) most filesystems accept.
+const MAX_LEN: usize = 255;
+/// Longer extensions aren't kept when truncating.
+const MAX_EXTENSION_LEN: usize = 32;
+const FALLBACK: &str = "download";
+
+/// Device names Windows doesn't allow as filenames, regardless of the extension.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Only the last path component is kept, control characters are dropped,
+/// characters Windows doesn't allow are replaced and the name is shortened to 255 bytes.
+pub fn sanitize(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+
+    let mut name = escape_reserved(trim(&name));
+    if name.len() > MAX_LEN {
+        name = truncate(&name);
+    }
+    match trim(&name) {
+        "" => FALLBACK.to_owned(),
+        trimmed => escape_reserved(trimmed),
+    }
+}
+
+/// A `Content-Disposition` with an ASCII `filename` and the full UTF-8 `filename*` (RFC 6266).
+pub fn content_disposition(disposition: DispositionType, filename: &str) -> ContentDisposition {
+    let filename = sanitize(filename);
+    ContentDisposition {
+        disposition,
+        parameters: vec![
+            DispositionParam::Filename(ascii_fallback(&filename)),
+            DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_owned()),
+                language_tag: None,
+                value: filename.into_bytes(),
+            }),
+        ],
+    }
+}
+
+/// Windows drops trailing dots and spaces, leading spaces are mostly accidental.
+fn trim(name: &str) -> &str {
+    name.trim_start()
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+}
+
+fn escape_reserved(name: &str) -> String {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    match RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        true => format!("_{name}"),
+        false => name.to_owned(),
+    }
+}
+
+fn truncate(name: &str) -> String {
+    let extension = name
+        .rfind('.')
+        .filter(|&i| i > 0 && name.len() - i <= MAX_EXTENSION_LEN)
+        .map_or("", |i| &name[i..]);
+    let stem = &name[..name.len() - extension.len()];
+    let mut end = MAX_LEN - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{extension}", &stem[..end])
+}
+
+/// Replaces everything that's not printable ASCII, `%` is replaced too, as some browsers decode it.
+fn ascii_fallback(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            ' '..='~' if c != '%' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{self, Header};
+    use proptest::prelude::*;
+
+    #[test]
+    fn sanitizes_filenames() {
+        assert_eq!(sanitize("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize(r"C:\Users\me\photo.jpg"), "photo.jpg");
+        assert_eq!(sanitize("a\u{0}b\nc.txt"), "abc.txt");
+        assert_eq!(sanitize("what?<now>.txt"), "what__now_.txt");
+        assert_eq!(sanitize("con.txt"), "_con.txt");
+        assert_eq!(sanitize("LPT1"), "_LPT1");
+        assert_eq!(sanitize("console.txt"), "console.txt");
+        assert_eq!(sanitize(" name. . "), "name");
+        assert_eq!(sanitize(".."), "download");
+        assert_eq!(sanitize("dir/"), "download");
+        assert_eq!(sanitize("日本語.txt"), "日本語.txt");
+
+        let long = format!("{}.tar.gz", "ä".repeat(200));
+        let short = sanitize(&long);
+        assert!(short.len() <= MAX_LEN);
+        assert!(short.ends_with("ä.gz"));
+    }
+
+    #[test]
+    fn emits_both_filename_parameters() {
+        let cd = content_disposition(DispositionType::Attachment, "naïve 100%.txt");
+        assert_eq!(
+            cd.to_string(),
+            "attachment; filename=\"na_ve 100_.txt\"; filename*=UTF-8''na%C3%AFve%20100%25.txt"
+        );
+    }
+
+    fn parse(cd: &ContentDisposition) -> ContentDisposition {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::CONTENT_DISPOSITION, cd.to_string()))
+            .to_http_request();
+        ContentDisposition::parse(&req).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn sanitized_names_are_safe(name in "(?s).{0,300}") {
+            let sanitized = sanitize(&name);
+            prop_assert!(!sanitized.is_empty());
+            prop_assert!(sanitized.len() <= MAX_LEN);
+            prop_assert!(!sanitized.chars().any(|c| c.is_control() || r#"/\<>:"|?*"#.contains(c)));
+            prop_assert!(!sanitized.ends_with(['.', ' ']));
+            prop_assert!(!RESERVED.iter().any(|r| r.eq_ignore_ascii_case(
+                sanitized.split('.').next().unwrap().trim_end()
+            )));
+            prop_assert_eq!(sanitize(&sanitized), sanitized);
+        }
+
+        #[test]
+        fn reserved_names_are_escaped(
+            reserved in proptest::sample::select(RESERVED),
+            extension in "(\\.[a-z]{1,4})?",
+        ) {
+            let name = format!("{}{extension}", reserved.to_lowercase());
+            prop_assert_eq!(sanitize(&name), format!("_{name}"));
+        }
+
+        #[test]
+        fn content_disposition_round_trips(name in "(?s).{0,300}") {
+            let cd = parse(&content_disposition(DispositionType::Attachment, &name));
+            let fallback = cd.get_filename().unwrap();
+            prop_assert!(fallback.chars().all(|c| c.is_ascii() && !c.is_ascii_control()));
+            prop_assert_eq!(
+                cd.get_filename_ext().map(|ext| ext.value.clone()),
+                Some(sanitize(&name).into_bytes())
+            );
+        }
+    }
+}
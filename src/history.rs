@@ -0,0 +1,147 @@
+use crate::{checksum::Digest, images::Thumbnail, status::TransferStatus};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+/// What's known about a transfer while it's open, turned into a [`HistoryEntry`] once it finishes.
+#[derive(Debug, Clone)]
+pub struct TransferRecord {
+    pub user: String,
+    pub filename: String,
+    pub created_at: OffsetDateTime,
+    pub receiver: Option<ReceiverDetails>,
+    /// The digest the sender declared when creating the transfer.
+    pub digest: Option<Digest>,
+    pub preview: bool,
+    pub declared_type: Option<mime::Mime>,
+    /// The content type detected from the payload's first bytes.
+    pub detected_type: Option<mime::Mime>,
+    pub thumbnail: Option<Arc<Thumbnail>>,
+    pub device: Option<Uuid>,
+}
+
+/// Who downloaded a transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiverDetails {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// A finished transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub id: Uuid,
+    pub filename: String,
+    /// The number of bytes the sender sent.
+    pub size: u64,
+    /// Unix timestamp in seconds.
+    pub created_at: i64,
+    /// Unix timestamp in seconds.
+    pub completed_at: i64,
+    pub receiver: Option<ReceiverDetails>,
+    pub outcome: TransferStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub declared_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detected_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum OutcomeFilter {
+    Completed,
+    Failed,
+}
+
+/// The query of `GET /api/v1/history`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// The number of (matching) entries to skip.
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub outcome: Option<OutcomeFilter>,
+    /// Only entries whose filename contains this (case-insensitive).
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Only entries completed at or after this unix timestamp.
+    #[serde(default)]
+    pub since: Option<i64>,
+    /// Only entries completed before this unix timestamp.
+    #[serde(default)]
+    pub until: Option<i64>,
+}
+
+/// A page of history entries, newest first.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// Whether more (older) entries match the query.
+    pub more: bool,
+}
+
+impl TransferRecord {
+    pub fn new(user: String, filename: String, digest: Option<Digest>) -> Self {
+        Self {
+            user,
+            filename,
+            created_at: OffsetDateTime::now_utc(),
+            receiver: None,
+            digest,
+            preview: false,
+            declared_type: None,
+            detected_type: None,
+            thumbnail: None,
+            device: None,
+        }
+    }
+
+    pub fn finish(&self, id: Uuid, size: u64, outcome: TransferStatus) -> HistoryEntry {
+        HistoryEntry {
+            id,
+            filename: self.filename.clone(),
+            size,
+            created_at: self.created_at.unix_timestamp(),
+            completed_at: OffsetDateTime::now_utc().unix_timestamp(),
+            receiver: self.receiver.clone(),
+            outcome,
+            declared_type: self.declared_type.as_ref().map(ToString::to_string),
+            detected_type: self.detected_type.as_ref().map(ToString::to_string),
+        }
+    }
+}
+
+impl HistoryQuery {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        let outcome = match self.outcome {
+            Some(OutcomeFilter::Completed) => entry.outcome.is_completed(),
+            Some(OutcomeFilter::Failed) => !entry.outcome.is_completed(),
+            None => true,
+        };
+        let filename = self
+            .filename
+            .as_ref()
+            .is_none_or(|f| entry.filename.to_lowercase().contains(&f.to_lowercase()));
+
+        outcome
+            && filename
+            && self.since.is_none_or(|t| entry.completed_at >= t)
+            && self.until.is_none_or(|t| entry.completed_at < t)
+    }
+}
@@ -0,0 +1,260 @@
+use bytes::{Bytes, BytesMut};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageError};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImageConfig {
+    #[serde(default = "default_thumbnails")]
+    pub thumbnails: bool,
+    #[serde(default = "default_max_source_size")]
+    pub max_source_size: usize,
+    #[serde(default = "default_thumbnail_size")]
+    pub thumbnail_size: u32,
+    #[serde(default)]
+    pub strip_metadata: bool,
+}
+
+fn default_thumbnails() -> bool {
+    true
+}
+
+fn default_max_source_size() -> usize {
+    20 * 1024 * 1024
+}
+
+fn default_thumbnail_size() -> u32 {
+    256
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            thumbnails: default_thumbnails(),
+            max_source_size: default_max_source_size(),
+            thumbnail_size: default_thumbnail_size(),
+            strip_metadata: false,
+        }
+    }
+}
+
+/// What's known about an image before it's transferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageDetails {
+    pub width: u32,
+    pub height: u32,
+    /// In bytes.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub details: ImageDetails,
+    /// The thumbnail, always a JPEG.
+    pub jpeg: Bytes,
+}
+
+impl Thumbnail {
+    /// This is CPU bound, call it on a blocking thread.
+    pub fn generate(image: &[u8], max_side: u32) -> Result<Self, ImageError> {
+        let decoded = image::load_from_memory(image)?;
+        let details = ImageDetails {
+            width: decoded.width(),
+            height: decoded.height(),
+            size: image.len() as u64,
+        };
+        // JPEG has no alpha channel
+        let thumbnail = DynamicImage::ImageRgb8(decoded.thumbnail(max_side, max_side).to_rgb8());
+        let mut jpeg = Vec::new();
+        thumbnail.write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 80))?;
+
+        Ok(Self {
+            details,
+            jpeg: jpeg.into(),
+        })
+    }
+}
+
+/// Anything that doesn't start like a JPEG, and everything after the
+/// first scan, is passed through unchanged. Note that this also removes the
+/// EXIF orientation, so some viewers show stripped photos rotated.
+#[derive(Debug, Default)]
+pub struct MetadataStripper {
+    state: State,
+    /// The bytes of a marker (and its length) that's split across chunks.
+    header: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Start,
+    Marker,
+    Copy(usize),
+    Skip(usize),
+    Rest,
+}
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOS: u8 = 0xda;
+/// EXIF and XMP.
+const APP1: u8 = 0xe1;
+/// IPTC.
+const APP13: u8 = 0xed;
+const COM: u8 = 0xfe;
+
+impl MetadataStripper {
+    pub fn is_jpeg(start: &[u8]) -> bool {
+        start.starts_with(&[0xff, SOI, 0xff])
+    }
+
+    pub fn push(&mut self, mut chunk: &[u8]) -> Bytes {
+        let mut out = BytesMut::with_capacity(chunk.len());
+        while !chunk.is_empty() {
+            match self.state {
+                State::Rest => {
+                    out.extend_from_slice(chunk);
+                    break;
+                }
+                State::Copy(n) | State::Skip(n) => {
+                    let k = n.min(chunk.len());
+                    if let State::Copy(_) = self.state {
+                        out.extend_from_slice(&chunk[..k]);
+                    }
+                    chunk = &chunk[k..];
+                    self.state = match (self.state, n - k) {
+                        (_, 0) => State::Marker,
+                        (State::Copy(_), n) => State::Copy(n),
+                        (_, n) => State::Skip(n),
+                    };
+                }
+                State::Start | State::Marker => {
+                    self.header.push(chunk[0]);
+                    chunk = &chunk[1..];
+                    self.next_marker(&mut out);
+                }
+            }
+        }
+        out.freeze()
+    }
+
+    /// The bytes held back at the end of the stream, only non-empty for truncated JPEGs.
+    pub fn finish(&mut self) -> Bytes {
+        self.state = State::Rest;
+        std::mem::take(&mut self.header).into()
+    }
+
+    fn next_marker(&mut self, out: &mut BytesMut) {
+        let header = &self.header;
+        let next = match (self.state, header.as_slice()) {
+            (_, [_]) => return,
+            (State::Start, [0xff, SOI]) => State::Marker,
+            (State::Start, _) | (_, [0x00..=0xfe, _]) => State::Rest,
+            // fill bytes
+            (_, [0xff, 0xff]) => {
+                out.extend_from_slice(&[0xff]);
+                self.header.remove(0);
+                return;
+            }
+            (_, [0xff, SOS | EOI]) => State::Rest,
+            // markers without a length
+            (_, [0xff, 0x01 | 0xd0..=SOI]) => State::Marker,
+            (_, [0xff, _, ..]) if header.len() < 4 => return,
+            (_, [0xff, marker, hi, lo]) => {
+                let len = (u16::from_be_bytes([*hi, *lo]) as usize).saturating_sub(2);
+                if matches!(*marker, APP1 | APP13 | COM) {
+                    self.header.clear();
+                    self.state = State::Skip(len);
+                    if len == 0 {
+                        self.state = State::Marker;
+                    }
+                    return;
+                }
+                match len {
+                    0 => State::Marker,
+                    len => State::Copy(len),
+                }
+            }
+            _ => unreachable!("markers are handled once they're complete"),
+        };
+        out.extend_from_slice(&self.header);
+        self.header.clear();
+        self.state = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    fn jpeg(segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut jpeg = vec![0xff, SOI];
+        for segment in segments {
+            jpeg.extend_from_slice(segment);
+        }
+        jpeg.extend_from_slice(&[0xff, SOS, 0, 2, 0xe1, 0xff, 0x00, 0xff, EOI]);
+        jpeg
+    }
+
+    fn strip(input: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut stripper = MetadataStripper::default();
+        let mut out = Vec::new();
+        for chunk in input.chunks(chunk_size) {
+            out.extend_from_slice(&stripper.push(chunk));
+        }
+        out.extend_from_slice(&stripper.finish());
+        out
+    }
+
+    #[test]
+    fn strips_metadata_segments_across_chunks() {
+        let jfif = segment(0xe0, b"JFIF\0\x01\x02");
+        let exif = segment(APP1, b"Exif\0\0GPS data");
+        let comment = segment(COM, b"a comment");
+        let input = jpeg(&[jfif.clone(), exif, segment(APP13, b"IPTC"), comment]);
+        let expected = jpeg(&[jfif]);
+
+        for chunk_size in 1..=input.len() {
+            assert_eq!(strip(&input, chunk_size), expected, "{chunk_size}");
+        }
+    }
+
+    #[test]
+    fn passes_through_other_payloads() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(strip(png, 3), png);
+        assert!(!MetadataStripper::is_jpeg(png));
+        assert_eq!(strip(&[0xff, SOI, 0xff], 1), [0xff, SOI, 0xff]);
+    }
+
+    #[test]
+    fn generates_thumbnails() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgba8(1000, 500)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let thumbnail = Thumbnail::generate(&png, 100).unwrap();
+        assert_eq!(
+            thumbnail.details,
+            ImageDetails {
+                width: 1000,
+                height: 500,
+                size: png.len() as u64
+            }
+        );
+        let decoded = image::load_from_memory(&thumbnail.jpeg).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (100, 50));
+        assert!(Thumbnail::generate(b"not an image", 100).is_err());
+    }
+}
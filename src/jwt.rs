@@ -18,15 +18,15 @@ pub type EncodeConfig = (jsonwebtoken::EncodingKey, jsonwebtoken::Header);
 pub type DecodeConfig = (jsonwebtoken::DecodingKey, jsonwebtoken::Validation);
 
 impl TransferClaims {
-    pub fn sender(id: Uuid, duration: time::Duration) -> Self {
-        Self::new(TransferRole::Sender, id, duration)
+    pub fn sender(id: Uuid, expires_at: time::OffsetDateTime) -> Self {
+        Self::new(TransferRole::Sender, id, expires_at)
     }
 
-    pub fn new(role: TransferRole, id: Uuid, duration: time::Duration) -> Self {
+    pub fn new(role: TransferRole, id: Uuid, expires_at: time::OffsetDateTime) -> Self {
         Self {
             role,
             id,
-            exp: (time::OffsetDateTime::now_utc() + duration).unix_timestamp(),
+            exp: expires_at.unix_timestamp(),
         }
     }
 }
@@ -0,0 +1,1367 @@
+//! Relays files from senders to receivers without storing them.
+//!
+//! [`Frachter`] provides the server's routes to run them on their own or mount them into another
+//! actix app, the `frachter` binary is a thin wrapper around it.
+
+mod app;
+mod assets;
+pub mod bind;
+pub mod bounded_body;
+mod checksum;
+mod cleanup;
+mod compression;
+pub mod config;
+mod devices;
+mod expiry;
+mod filename;
+mod history;
+mod images;
+pub mod jwt;
+mod lifetime;
+pub mod mdns;
+mod metrics;
+mod middleware;
+mod mutex;
+mod openapi;
+mod preview;
+mod progress;
+mod proxy;
+mod quota;
+mod relay;
+mod scan;
+mod serde_util;
+mod sniff;
+mod status;
+mod store;
+pub mod transfer;
+mod upload;
+mod ws;
+
+pub use app::{BuildError, Frachter, FrachterBuilder};
+
+use crate::{
+    checksum::{BadDigest, ChecksumMismatch, Digest, REPR_DIGEST},
+    cleanup::{Cleanup, GetStatus, PutStatus, TrackPairing, TrackTransfer},
+    compression::{Coding, Compression},
+    devices::{Device, Devices, Offer, PairedDevice, PairedDeviceError, Pairing, DEVICE_COOKIE},
+    history::{HistoryPage, HistoryQuery, ReceiverDetails, TransferRecord},
+    images::{ImageConfig, ImageDetails, Thumbnail},
+    jwt::{EncodeConfig, TransferClaims},
+    lifetime::LifetimeConfig,
+    middleware::{JwtDecodeError, OriginError, RequireTokenError, User},
+    openapi::{ApiDoc, API_VERSION},
+    preview::Viewer,
+    progress::TransferProgress,
+    proxy::ProxyConfig,
+    quota::{QuotaError, Quotas},
+    relay::{BodyUpload, Relay, RelayError},
+    scan::{ScanRejected, Scanner},
+    sniff::{ContentTypeConfig, ContentTypeMismatch},
+    status::{FailureReason, TransferStatus},
+    store::{Store, StoreError, StoredTransfer},
+    transfer::{
+        Abort, Aborted, ReceiverInfo, SendTransfer, SenderInfo, TransferAborted, TransferSender,
+        Transfers, MAX_REASON_LEN,
+    },
+    upload::{UploadLimits, UploadLimitsOverride, UploadTimeout},
+};
+use actix::Addr;
+use actix_web::{
+    cookie::{CookieBuilder, SameSite},
+    delete,
+    dev::Service,
+    error::PayloadError,
+    get,
+    http::{
+        header,
+        header::{AcceptEncoding, ContentEncoding, ContentType, DispositionType},
+    },
+    post, put, web,
+    web::{Payload, ReqData},
+    HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use actix_web_lab::sse;
+use bytes::BytesMut;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::{openapi::Server, IntoParams, OpenApi, ToSchema};
+use uuid::Uuid;
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CreateTransfer {
+    id: Uuid,
+    /// Where receivers get the transfer.
+    url: String,
+    /// Unix timestamp in seconds.
+    expires_at: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CreateTransferBody {
+    filename: String,
+    /// Derived from the filename and the payload if missing.
+    #[serde(default, deserialize_with = "serde_util::mime::deserialize_optional")]
+    #[schema(value_type = Option<String>)]
+    content_type: Option<mime::Mime>,
+    /// In seconds.
+    #[serde(default)]
+    expires_in: Option<u64>,
+    /// The digest the payload has to match.
+    #[serde(default)]
+    digest: Option<Digest>,
+    /// Show the payload inline if its type allows it, receivers can override this.
+    #[serde(default)]
+    preview: bool,
+    /// A paired device of the user, its inbox is notified about the transfer.
+    #[serde(default)]
+    device: Option<Uuid>,
+    #[serde(flatten)]
+    limits: UploadLimitsOverride,
+}
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+#[status(500)]
+enum CreateTransferError {
+    #[error("Couldn't create jwt")]
+    Jwt,
+    #[error("Couldn't create cleanup")]
+    Actix,
+    #[error(transparent)]
+    #[status(transparent)]
+    Quota(#[from] QuotaError),
+    #[error("This device doesn't exist")]
+    #[status(400)]
+    UnknownDevice,
+    #[error(transparent)]
+    #[status(400)]
+    BadDigest(#[from] BadDigest),
+}
+
+openapi::error_responses!(
+    CreateTransferError,
+    [
+        RequireTokenError::BadToken,
+        CreateTransferError::UnknownDevice,
+        CreateTransferError::BadDigest(BadDigest("md5=:x:".into())),
+        QuotaError::FileTooLarge(0),
+        QuotaError::TooManyUserTransfers(0),
+        QuotaError::TooManyTransfers,
+        QuotaError::DailyBudgetExceeded(0),
+        CreateTransferError::Jwt,
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+enum WaitTransferError {
+    #[error("This transfer doesn't exist or is started already")]
+    #[status(400)]
+    NoTransfer,
+    #[error("No receiver connected in 60s, try again")]
+    #[status(504)]
+    Timeout,
+    #[error("The transfer was closed")]
+    #[status(400)]
+    TransferClosed,
+}
+
+openapi::error_responses!(
+    WaitTransferError,
+    [
+        RequireTokenError::BadToken,
+        JwtDecodeError::BadToken,
+        WaitTransferError::NoTransfer,
+        WaitTransferError::TransferClosed,
+        WaitTransferError::Timeout,
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+#[status(400)]
+enum TransferEventsError {
+    #[error("This transfer doesn't exist or is finished already")]
+    NoTransfer,
+}
+
+openapi::error_responses!(
+    TransferEventsError,
+    [
+        JwtDecodeError::BadToken,
+        OriginError::CrossOrigin,
+        TransferEventsError::NoTransfer,
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+#[status(400)]
+enum CancelTransferError {
+    #[error("This transfer doesn't exist or is finished already")]
+    NoTransfer,
+}
+
+openapi::error_responses!(
+    CancelTransferError,
+    [
+        RequireTokenError::BadToken,
+        JwtDecodeError::BadToken,
+        CancelTransferError::NoTransfer,
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+#[status(400)]
+enum DeclineError {
+    #[error("This transfer doesn't exist or is finished already")]
+    NoTransfer,
+    #[error("Reasons can be up to {MAX_REASON_LEN} characters long")]
+    BadReason,
+}
+
+openapi::error_responses!(
+    DeclineError,
+    [DeclineError::NoTransfer, DeclineError::BadReason]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+enum ReceiveError {
+    #[error("This transfer doesn't exist")]
+    #[status(400)]
+    NoTransfer,
+    #[error("The sender disconnected before sending the first byte")]
+    #[status(400)]
+    SenderDisconnected,
+}
+
+openapi::error_responses!(
+    ReceiveError,
+    [ReceiveError::NoTransfer, ReceiveError::SenderDisconnected]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+enum SendError {
+    #[error(transparent)]
+    #[status(400)]
+    BadDigest(#[from] BadDigest),
+    #[error("The content encoding isn't supported")]
+    #[status(415)]
+    UnsupportedEncoding,
+    #[error(transparent)]
+    #[status(transparent)]
+    Relay(#[from] RelayError),
+}
+
+openapi::error_responses!(
+    SendError,
+    [
+        RequireTokenError::BadToken,
+        JwtDecodeError::BadToken,
+        RelayError::ReceiverDisconnected,
+        RelayError::Payload(PayloadError::Overflow),
+        RelayError::Timeout(UploadTimeout::Idle),
+        SendError::BadDigest(BadDigest("md5=:x:".into())),
+        RelayError::ChecksumMismatch(ChecksumMismatch("sha-256")),
+        QuotaError::FileTooLarge(0),
+        QuotaError::DailyBudgetExceeded(0),
+        SendError::UnsupportedEncoding,
+        RelayError::ContentTypeMismatch(ContentTypeMismatch {
+            declared: "image/png".into(),
+            detected: "application/pdf".into(),
+        }),
+        RelayError::ScanRejected(ScanRejected::Infected("EICAR".into())),
+        RelayError::ScanRejected(ScanRejected::Failed("timeout".into())),
+        RelayError::Cancelled(None),
+        RelayError::Declined(None),
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+enum GetStatusError {
+    #[error("There's no status for this transfer (yet)")]
+    #[status(404)]
+    NoStatus,
+    #[error("Couldn't reach cleanup")]
+    #[status(500)]
+    Actix,
+}
+
+openapi::error_responses!(
+    GetStatusError,
+    [
+        RequireTokenError::BadToken,
+        JwtDecodeError::BadToken,
+        GetStatusError::NoStatus,
+        GetStatusError::Actix,
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+enum HistoryError {
+    #[error("The history isn't enabled on this server")]
+    #[status(404)]
+    Disabled,
+    #[error("Couldn't read the history: {0}")]
+    #[status(500)]
+    Store(#[from] StoreError),
+    #[error("Internal error")]
+    #[status(500)]
+    Blocking,
+}
+
+openapi::error_responses!(
+    HistoryError,
+    [
+        RequireTokenError::BadToken,
+        HistoryError::Disabled,
+        HistoryError::Store(StoreError::Json(serde::de::Error::custom("..."))),
+        HistoryError::Blocking,
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+enum ThumbnailError {
+    #[error("Thumbnails are disabled")]
+    #[status(404)]
+    Disabled,
+    #[error("This transfer doesn't exist or already started")]
+    #[status(400)]
+    NoTransfer,
+    #[error("The image is too large for a thumbnail")]
+    #[status(413)]
+    TooLarge,
+    #[error("The payload couldn't be processed: {0}")]
+    #[status(400)]
+    PayloadError(PayloadError),
+    #[error("Couldn't read the image: {0}")]
+    #[status(415)]
+    BadImage(String),
+    #[error("Internal error")]
+    #[status(500)]
+    Blocking,
+}
+
+openapi::error_responses!(
+    ThumbnailError,
+    [
+        RequireTokenError::BadToken,
+        JwtDecodeError::BadToken,
+        ThumbnailError::NoTransfer,
+        ThumbnailError::PayloadError(PayloadError::Overflow),
+        ThumbnailError::Disabled,
+        ThumbnailError::TooLarge,
+        ThumbnailError::BadImage("unknown format".into()),
+        ThumbnailError::Blocking,
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+#[status(404)]
+enum GetThumbnailError {
+    #[error("This transfer doesn't exist")]
+    NoTransfer,
+    #[error("This transfer has no thumbnail")]
+    NoThumbnail,
+}
+
+openapi::error_responses!(
+    GetThumbnailError,
+    [
+        GetThumbnailError::NoTransfer,
+        GetThumbnailError::NoThumbnail
+    ]
+);
+
+#[derive(Deserialize, ToSchema)]
+struct StartPairingBody {
+    /// Shown to the user when choosing where to send a transfer.
+    name: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct StartPairing {
+    #[serde(flatten)]
+    pairing: Pairing,
+    /// The page that pairs the device.
+    url: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct PairDeviceBody {
+    code: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PairDevice {
+    device: Device,
+    /// Also set as a cookie, for clients that don't keep cookies.
+    token: String,
+}
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+enum DeviceError {
+    #[error(
+        "Device names have to be 1 to {} characters long",
+        devices::MAX_NAME_LEN
+    )]
+    #[status(400)]
+    BadName,
+    #[error("This pairing code doesn't exist or expired")]
+    #[status(400)]
+    BadCode,
+    #[error("This device doesn't exist")]
+    #[status(404)]
+    NoDevice,
+}
+
+openapi::error_responses!(
+    DeviceError,
+    [
+        RequireTokenError::BadToken,
+        DeviceError::BadName,
+        DeviceError::BadCode,
+        DeviceError::NoDevice,
+    ]
+);
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+#[status(400)]
+enum SendWsError {
+    #[error("Couldn't establish a WebSocket connection: {0}")]
+    Handshake(String),
+}
+
+openapi::error_responses!(
+    SendWsError,
+    [
+        JwtDecodeError::BadToken,
+        OriginError::CrossOrigin,
+        SendWsError::Handshake("missing upgrade header".into()),
+    ]
+);
+
+#[utoipa::path(
+    put,
+    path = "/transfers",
+    tag = "transfers",
+    request_body = CreateTransferBody,
+    responses(
+        (status = 200, description = "The transfer's cookie is set for the next steps", body = CreateTransfer),
+        CreateTransferError,
+    ),
+    security(("token" = [])),
+)]
+#[put("")]
+#[allow(clippy::too_many_arguments)]
+async fn create_transfer(
+    transfers: web::Data<Transfers>,
+    web::Json(body): web::Json<CreateTransferBody>,
+    cleanup: web::Data<Addr<Cleanup>>,
+    encode_config: web::Data<EncodeConfig>,
+    limits: web::Data<UploadLimits>,
+    quotas: web::Data<Quotas>,
+    lifetimes: web::Data<LifetimeConfig>,
+    store: web::Data<Store>,
+    devices: web::Data<Devices>,
+    proxy: web::Data<ProxyConfig>,
+    user: ReqData<User>,
+    req: HttpRequest,
+) -> Result<HttpResponse, CreateTransferError> {
+    if let Some(device) = &body.device {
+        if !devices.belongs_to(device, &user.0) {
+            return Err(CreateTransferError::UnknownDevice);
+        }
+    }
+    let permit = quotas.acquire(&user.0)?;
+    let lifetime = lifetimes.transfer(body.expires_in);
+    let limits = limits.restrict(&body.limits);
+    let digest = body.digest.map(Digest::supported).transpose()?;
+    let filename = filename::sanitize(&body.filename);
+    let content_type = sniff::provisional(body.content_type.as_ref(), &filename);
+    let record = TransferRecord {
+        preview: body.preview,
+        declared_type: body.content_type,
+        device: body.device,
+        ..TransferRecord::new(user.0.clone(), filename, digest)
+    };
+    let stored = StoredTransfer {
+        filename: record.filename.clone(),
+        content_type: content_type.to_string(),
+        user: record.user.clone(),
+        limits,
+        digest: record.digest.clone(),
+        preview: record.preview,
+        declared_type: record.declared_type.as_ref().map(ToString::to_string),
+        device: record.device,
+        created_at: record.created_at.unix_timestamp(),
+        expires_at: lifetime.expires_at.unix_timestamp(),
+    };
+    let offer = record
+        .device
+        .map(|device| (device, record.clone(), content_type.clone()));
+    let id = Uuid::new_v4();
+    // everything that can fail comes before the transfer is inserted, so nothing is left behind
+    let token = jwt::encode_token(
+        &encode_config,
+        &TransferClaims::sender(id, lifetime.expires_at),
+    )
+    .map_err(|_| CreateTransferError::Jwt)?;
+    cleanup
+        .send(TrackTransfer(id, lifetime.deadline))
+        .await
+        .map_err(|_| CreateTransferError::Actix)?;
+    transfers.insert_transfer(id, record, content_type, limits, permit);
+    store.put_transfer(id, &stored);
+    if let Some((device, record, content_type)) = offer {
+        devices.offer(Offer::new(device, id, &record, &content_type));
+    }
+
+    Ok(HttpResponse::Ok()
+        .cookie(
+            CookieBuilder::new("frachter-transfer", token)
+                .expires(lifetime.expires_at)
+                .http_only(true)
+                // the routes that only check the cookie must not get it from other sites
+                .same_site(SameSite::Strict)
+                .finish(),
+        )
+        .json(CreateTransfer {
+            id,
+            url: format!(
+                "{}/api/{API_VERSION}/receive/{id}{}",
+                proxy.public_url(&req),
+                if body.preview { "/view" } else { "" }
+            ),
+            expires_at: lifetime.expires_at.unix_timestamp(),
+        }))
+}
+
+/// Waits up to 60 seconds for a receiver.
+#[utoipa::path(
+    get,
+    path = "/transfer/wait",
+    tag = "transfers",
+    responses(
+        (status = 204, description = "A receiver is waiting for the payload"),
+        WaitTransferError,
+    ),
+    security(("token" = [], "transfer" = [])),
+)]
+#[get("/wait")]
+async fn wait_transfer(
+    transfers: web::Data<Transfers>,
+    claims: ReqData<TransferClaims>,
+) -> Result<HttpResponse, WaitTransferError> {
+    let mut rx = transfers
+        .receiver_rx(&claims.id)
+        .ok_or(WaitTransferError::NoTransfer)?;
+    match tokio::time::timeout(Duration::from_secs(60), rx.changed()).await {
+        Ok(Ok(_)) => match *rx.borrow() {
+            true => Ok(HttpResponse::NoContent().finish()),
+            false => Err(WaitTransferError::Timeout),
+        },
+        Ok(Err(_)) => Err(WaitTransferError::TransferClosed),
+        Err(_) => Err(WaitTransferError::Timeout),
+    }
+}
+
+/// Streams the transfer's progress.
+///
+/// Every event is named after the `type` of its data, the stream ends once the transfer completed
+/// or the receiver disconnected.
+#[utoipa::path(
+    get,
+    path = "/transfer/events",
+    tag = "transfers",
+    responses(
+        (status = 200, description = "Server-sent events", content_type = "text/event-stream", body = TransferProgress),
+        TransferEventsError,
+    ),
+    security(("transfer" = [])),
+)]
+#[get("")]
+async fn transfer_events(
+    transfers: web::Data<Transfers>,
+    claims: ReqData<TransferClaims>,
+) -> Result<impl Responder, TransferEventsError> {
+    let rx = transfers
+        .progress_rx(&claims.id)
+        .ok_or(TransferEventsError::NoTransfer)?;
+    // emit the current state first, then every change until a final state is reached
+    let events = futures::stream::unfold(Some((rx, true)), |state| async move {
+        let (mut rx, first) = state?;
+        if !first && rx.changed().await.is_err() {
+            return None;
+        }
+        let progress = rx.borrow().clone();
+        let event = sse::Data::new_json(&progress)
+            .map(|data| sse::Event::Data(data.event(progress.event_name())));
+        let next = (!progress.is_final()).then_some((rx, false));
+        Some((event, next))
+    });
+
+    Ok(sse::Sse::from_stream(events).with_keep_alive(Duration::from_secs(15)))
+}
+
+/// Cancels the transfer, before or while it's sending.
+///
+/// A waiting sender and a connected receiver get an error, the transfer's status is
+/// `senderCancelled`.
+#[utoipa::path(
+    delete,
+    path = "/transfer",
+    tag = "transfers",
+    responses(
+        (status = 204, description = "The transfer is cancelled"),
+        CancelTransferError,
+    ),
+    security(("token" = [], "transfer" = [])),
+)]
+#[delete("")]
+async fn cancel_transfer(
+    transfers: web::Data<Transfers>,
+    cleanup: web::Data<Addr<Cleanup>>,
+    store: web::Data<Store>,
+    claims: ReqData<TransferClaims>,
+) -> Result<HttpResponse, CancelTransferError> {
+    match transfers
+        .abort(&claims.id, Abort::Cancelled)
+        .ok_or(CancelTransferError::NoTransfer)?
+    {
+        Aborted::Pending { record, sender } => {
+            finish_aborted(
+                &cleanup,
+                &store,
+                claims.id,
+                &record,
+                sender,
+                Abort::Cancelled,
+            )
+            .await;
+        }
+        // the sender's request notices and finishes the transfer
+        Aborted::Running => (),
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn finish_aborted(
+    cleanup: &Addr<Cleanup>,
+    store: &Store,
+    id: Uuid,
+    record: &TransferRecord,
+    sender: Option<TransferSender>,
+    abort: Abort,
+) {
+    if let Some(sender) = sender {
+        sender
+            .close(Some(TransferAborted(abort.message())))
+            .await
+            .ok();
+    }
+    let status = abort.status();
+    store.put_history(&record.user, &record.finish(id, 0, status.clone()));
+    cleanup.send(PutStatus(id, status)).await.ok();
+}
+
+#[derive(Deserialize, ToSchema)]
+struct DeclineBody {
+    /// Shown to the sender, e.g. `not enough storage`.
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Declines the transfer, before or while receiving it.
+///
+/// The sender's events end with `receiverDeclined` and the transfer's status is
+/// `receiverDeclined` with the reason as its `message`. Unlike a receiver that just disconnects,
+/// this is on purpose, so the sender doesn't have to retry.
+///
+/// Like `GET /receive/{id}`, this only needs the transfer's id, so anyone who got the link can
+/// decline the transfer.
+#[utoipa::path(
+    post,
+    path = "/receive/{id}/decline",
+    tag = "receive",
+    params(("id" = Uuid, Path)),
+    request_body = DeclineBody,
+    responses(
+        (status = 204, description = "The transfer is declined"),
+        DeclineError,
+    ),
+)]
+#[post("/{id}/decline")]
+async fn decline_transfer(
+    transfers: web::Data<Transfers>,
+    cleanup: web::Data<Addr<Cleanup>>,
+    store: web::Data<Store>,
+    id: web::Path<Uuid>,
+    web::Json(body): web::Json<DeclineBody>,
+) -> Result<HttpResponse, DeclineError> {
+    let reason = body
+        .reason
+        .map(|r| r.trim().to_owned())
+        .filter(|r| !r.is_empty());
+    if reason
+        .as_ref()
+        .is_some_and(|r| r.chars().count() > MAX_REASON_LEN)
+    {
+        return Err(DeclineError::BadReason);
+    }
+    tracing::info!(%id, reason, "The receiver declined the transfer");
+    let abort = Abort::Declined(reason);
+    match transfers
+        .abort(&id, abort.clone())
+        .ok_or(DeclineError::NoTransfer)?
+    {
+        Aborted::Pending { record, sender } => {
+            finish_aborted(&cleanup, &store, *id, &record, sender, abort).await;
+        }
+        // the sender's request notices and finishes the transfer
+        Aborted::Running => (),
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReceiveQuery {
+    /// Overrides the sender's choice of showing the payload inline.
+    #[serde(default)]
+    preview: Option<bool>,
+}
+
+/// Downloads the payload, the sender starts sending once a receiver is connected.
+#[utoipa::path(
+    get,
+    path = "/receive/{id}",
+    tag = "receive",
+    params(("id" = Uuid, Path), ReceiveQuery),
+    responses(
+        (status = 200, description = "The payload", content_type = "application/octet-stream", body = [u8]),
+        ReceiveError,
+    ),
+)]
+#[get("/{id}")]
+async fn receive(
+    transfers: web::Data<Transfers>,
+    compression: web::Data<Compression>,
+    id: web::Path<Uuid>,
+    query: web::Query<ReceiveQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ReceiveError> {
+    let receiver = ReceiverDetails {
+        ip: req
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_owned),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_owned),
+    };
+    let ReceiverInfo {
+        filename,
+        content_type,
+        preview,
+        body_info_rx,
+        body,
+    } = transfers
+        .receive(&id, 1, receiver)
+        .ok_or(ReceiveError::NoTransfer)?;
+    let body_info = match tokio::time::timeout(Duration::from_secs(5 * 60), body_info_rx).await {
+        Ok(Ok(x)) => x,
+        _ => return Err(ReceiveError::SenderDisconnected),
+    };
+    let content_type = body_info.content_type.unwrap_or(content_type);
+    let coding = compression.negotiate(
+        &req.get_header::<AcceptEncoding>()
+            .unwrap_or(AcceptEncoding(Vec::new())),
+        &content_type,
+        body_info.content_encoding,
+    );
+
+    let viewer = query
+        .preview
+        .unwrap_or(preview)
+        .then(|| Viewer::for_mime(&content_type))
+        .flatten();
+
+    let mut res = HttpResponse::Ok();
+    res.insert_header((
+        header::CONTENT_DISPOSITION,
+        filename::content_disposition(
+            match viewer {
+                Some(_) => DispositionType::Inline,
+                None => DispositionType::Attachment,
+            },
+            &filename,
+        ),
+    ))
+    .insert_header((header::CONTENT_TYPE, ContentType(content_type)));
+    if let Some(viewer) = viewer {
+        preview::lock_down(&mut res, viewer);
+    }
+    // the length and digest only apply to the payload as it was uploaded
+    if matches!(coding, Coding::Unchanged(_)) && !body_info.modified {
+        if let Some(length) = body_info.content_length {
+            res.insert_header((header::CONTENT_LENGTH, length));
+        }
+        // the payload is verified against this digest before the body completes
+        if let Some(digest) = &body_info.digest {
+            res.insert_header((REPR_DIGEST, digest.to_header()));
+        }
+    }
+
+    Ok(coding.respond(res, body))
+}
+
+async fn finish_transfer(
+    cleanup: &Addr<Cleanup>,
+    store: &Store,
+    id: Uuid,
+    info: &SenderInfo,
+    status: TransferStatus,
+) {
+    store.put_history(
+        &info.record.user,
+        &info.record.finish(id, info.permit.bytes(), status.clone()),
+    );
+    cleanup.send(PutStatus(id, status)).await.ok();
+}
+
+/// A page showing the payload inline, or the download itself if its type can't be previewed.
+#[utoipa::path(
+    get,
+    path = "/receive/{id}/view",
+    tag = "receive",
+    params(("id" = Uuid, Path)),
+    responses(
+        (status = 200, description = "The viewer", content_type = "text/html"),
+        (status = 303, description = "The type can't be shown, redirects to the download"),
+        ReceiveError,
+    ),
+)]
+#[get("/{id}/view")]
+async fn view(
+    transfers: web::Data<Transfers>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, ReceiveError> {
+    let pending = transfers.pending(&id).ok_or(ReceiveError::NoTransfer)?;
+    let details = pending.thumbnail.map(|t| t.details);
+    Ok(match Viewer::for_mime(&pending.content_type) {
+        Some(viewer) => preview::viewer_page(*id, &pending.filename, viewer, details),
+        None => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, format!("../{id}")))
+            .finish(),
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ReceiveDetails {
+    filename: String,
+    #[serde(with = "serde_util::mime")]
+    #[schema(value_type = String)]
+    content_type: mime::Mime,
+    /// Only known if the sender uploaded the image for a thumbnail.
+    image: Option<ImageDetails>,
+}
+
+/// What's known about the payload before it's received.
+#[utoipa::path(
+    get,
+    path = "/receive/{id}/info",
+    tag = "receive",
+    params(("id" = Uuid, Path)),
+    responses((status = 200, body = ReceiveDetails), ReceiveError),
+)]
+#[get("/{id}/info")]
+async fn receive_info(
+    transfers: web::Data<Transfers>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, ReceiveError> {
+    let pending = transfers.pending(&id).ok_or(ReceiveError::NoTransfer)?;
+    Ok(HttpResponse::Ok().json(ReceiveDetails {
+        filename: pending.filename,
+        content_type: pending.content_type,
+        image: pending.thumbnail.map(|t| t.details),
+    }))
+}
+
+/// The outcome of a transfer for its receiver, e.g. to show the content scanner's verdict.
+#[utoipa::path(
+    get,
+    path = "/receive/{id}/status",
+    tag = "receive",
+    params(("id" = Uuid, Path)),
+    responses((status = 200, body = TransferStatus), GetStatusError),
+)]
+#[get("/{id}/status")]
+async fn receive_status(
+    cleanup: web::Data<Addr<Cleanup>>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, GetStatusError> {
+    let status = cleanup
+        .send(GetStatus(*id))
+        .await
+        .map_err(|_| GetStatusError::Actix)?
+        .ok_or(GetStatusError::NoStatus)?;
+
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// A thumbnail of the image that's about to be received.
+#[utoipa::path(
+    get,
+    path = "/receive/{id}/thumbnail",
+    tag = "receive",
+    params(("id" = Uuid, Path)),
+    responses(
+        (status = 200, description = "The thumbnail", content_type = "image/jpeg", body = [u8]),
+        GetThumbnailError,
+    ),
+)]
+#[get("/{id}/thumbnail")]
+async fn get_thumbnail(
+    transfers: web::Data<Transfers>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, GetThumbnailError> {
+    let thumbnail = transfers
+        .pending(&id)
+        .ok_or(GetThumbnailError::NoTransfer)?
+        .thumbnail
+        .ok_or(GetThumbnailError::NoThumbnail)?;
+    Ok(HttpResponse::Ok()
+        .content_type(mime::IMAGE_JPEG)
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(thumbnail.jpeg.clone()))
+}
+
+/// Generates a thumbnail from the image that's about to be sent.
+#[utoipa::path(
+    post,
+    path = "/transfer/thumbnail",
+    tag = "transfers",
+    request_body(content = [u8], description = "The image", content_type = "application/octet-stream"),
+    responses((status = 200, body = ImageDetails), ThumbnailError),
+    security(("token" = [], "transfer" = [])),
+)]
+#[post("/thumbnail")]
+async fn upload_thumbnail(
+    transfers: web::Data<Transfers>,
+    images: web::Data<ImageConfig>,
+    claims: ReqData<TransferClaims>,
+    mut payload: Payload,
+) -> Result<HttpResponse, ThumbnailError> {
+    if !images.thumbnails {
+        return Err(ThumbnailError::Disabled);
+    }
+    let mut image = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(ThumbnailError::PayloadError)?;
+        if image.len() + chunk.len() > images.max_source_size {
+            return Err(ThumbnailError::TooLarge);
+        }
+        image.extend_from_slice(&chunk);
+    }
+    let max_side = images.thumbnail_size;
+    let thumbnail = web::block(move || Thumbnail::generate(&image, max_side))
+        .await
+        .map_err(|_| ThumbnailError::Blocking)?
+        .map_err(|e| ThumbnailError::BadImage(e.to_string()))?;
+    let details = thumbnail.details;
+    if !transfers.set_thumbnail(&claims.id, thumbnail) {
+        return Err(ThumbnailError::NoTransfer);
+    }
+
+    Ok(HttpResponse::Ok().json(details))
+}
+
+/// Sends the payload to the connected receiver.
+///
+/// A compressed payload is relayed as it is if the receiver accepts its encoding.
+#[utoipa::path(
+    post,
+    path = "/transfer/send",
+    tag = "transfers",
+    request_body(content = [u8], description = "The payload", content_type = "application/octet-stream"),
+    params(
+        ("content-encoding" = Option<String>, Header, description = "The payload's encoding, e.g. `gzip`"),
+        ("repr-digest" = Option<String>, Header, description = "Replaces the digest declared when creating the transfer"),
+    ),
+    responses(
+        (
+            status = 204,
+            description = "The receiver got the whole payload",
+            headers(("repr-digest" = String, description = "The digests of the relayed payload")),
+        ),
+        SendError,
+    ),
+    security(("token" = [], "transfer" = [])),
+)]
+#[post("/send")]
+#[allow(clippy::too_many_arguments)]
+async fn send(
+    cleanup: web::Data<Addr<Cleanup>>,
+    store: web::Data<Store>,
+    content_types: web::Data<ContentTypeConfig>,
+    images: web::Data<ImageConfig>,
+    scanner: web::Data<Option<Scanner>>,
+    SendTransfer(mut info): SendTransfer,
+    claims: ReqData<TransferClaims>,
+    payload: Payload,
+    req: HttpRequest,
+) -> Result<HttpResponse, SendError> {
+    // a digest sent with the payload replaces the one declared when creating the transfer
+    let expected = match req.headers().get(REPR_DIGEST).map(|h| {
+        h.to_str()
+            .map_err(|e| BadDigest(e.to_string()))
+            .and_then(Digest::from_header)
+            .and_then(Digest::supported)
+    }) {
+        Some(Ok(digest)) => Some(digest),
+        Some(Err(e)) => {
+            finish_transfer(
+                &cleanup,
+                &store,
+                claims.id,
+                &info,
+                TransferStatus::failed(FailureReason::PayloadError),
+            )
+            .await;
+            return Err(e.into());
+        }
+        None => None,
+    };
+    // compressed uploads are relayed as they are, the receiver's side decodes them if needed
+    let content_encoding = match req.headers().get(header::CONTENT_ENCODING) {
+        Some(h) => match h.to_str().ok().and_then(|s| s.parse().ok()) {
+            Some(enc) => enc,
+            None => {
+                finish_transfer(
+                    &cleanup,
+                    &store,
+                    claims.id,
+                    &info,
+                    TransferStatus::failed(FailureReason::PayloadError),
+                )
+                .await;
+                return Err(SendError::UnsupportedEncoding);
+            }
+        },
+        None => ContentEncoding::Identity,
+    };
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse::<usize>().ok());
+    if let Some(Err(e)) = content_length.map(|len| info.permit.check_size(len as u64)) {
+        finish_transfer(
+            &cleanup,
+            &store,
+            claims.id,
+            &info,
+            TransferStatus::failed(FailureReason::QuotaExceeded),
+        )
+        .await;
+        return Err(RelayError::from(e).into());
+    }
+    let scan = match scanner.as_ref() {
+        Some(scanner) => Some(scanner.start(claims.id, &info.record.filename).await),
+        None => None,
+    };
+    let mut relay = Relay::new(&mut info, &content_types, images.strip_metadata, scan);
+    // the receiver gets the body's info once the payload's type is known from its first bytes
+    let body_info = relay.info();
+    body_info.content_length = content_length;
+    body_info.content_encoding = content_encoding;
+    if let Some(digest) = expected {
+        body_info.digest = Some(digest);
+    }
+    let res = relay.run(&mut BodyUpload(payload)).await;
+    finish_transfer(&cleanup, &store, claims.id, &info, relay::status(&res)).await;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header((REPR_DIGEST, res?.digest.to_header()))
+        .finish())
+}
+
+/// The outcome of the transfer, kept for a while after it finished.
+#[utoipa::path(
+    get,
+    path = "/transfer/status",
+    tag = "transfers",
+    responses((status = 200, body = TransferStatus), GetStatusError),
+    security(("token" = [], "transfer" = [])),
+)]
+#[get("/status")]
+async fn transfer_status(
+    cleanup: web::Data<Addr<Cleanup>>,
+    claims: ReqData<TransferClaims>,
+) -> Result<HttpResponse, GetStatusError> {
+    let status = cleanup
+        .send(GetStatus(claims.id))
+        .await
+        .map_err(|_| GetStatusError::Actix)?
+        .ok_or(GetStatusError::NoStatus)?;
+
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// The user's finished transfers, newest first.
+#[utoipa::path(
+    get,
+    path = "/history",
+    tag = "history",
+    params(HistoryQuery),
+    responses((status = 200, body = HistoryPage), HistoryError),
+    security(("token" = [])),
+)]
+#[get("")]
+async fn get_history(
+    store: web::Data<Store>,
+    user: ReqData<User>,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, HistoryError> {
+    if !store.is_enabled() {
+        return Err(HistoryError::Disabled);
+    }
+    let page = web::block(move || store.history(&user.0, &query))
+        .await
+        .map_err(|_| HistoryError::Blocking)??;
+
+    Ok(HttpResponse::Ok().json(page))
+}
+
+/// Sends the payload over a WebSocket, see the README for its messages.
+#[utoipa::path(
+    get,
+    path = "/transfer/ws",
+    tag = "transfers",
+    responses(
+        (status = 101, description = "Switches to the WebSocket protocol"),
+        SendWsError,
+    ),
+    security(("transfer" = [])),
+)]
+#[get("")]
+#[allow(clippy::too_many_arguments)]
+async fn send_ws(
+    cleanup: web::Data<Addr<Cleanup>>,
+    store: web::Data<Store>,
+    content_types: web::Data<ContentTypeConfig>,
+    images: web::Data<ImageConfig>,
+    scanner: web::Data<Option<Scanner>>,
+    SendTransfer(mut info): SendTransfer,
+    claims: ReqData<TransferClaims>,
+    payload: Payload,
+    req: HttpRequest,
+) -> Result<HttpResponse, SendWsError> {
+    let (res, session, stream) = match actix_ws::handle(&req, payload) {
+        Ok(x) => x,
+        Err(e) => {
+            finish_transfer(
+                &cleanup,
+                &store,
+                claims.id,
+                &info,
+                TransferStatus::failed(FailureReason::SenderDisconnected),
+            )
+            .await;
+            return Err(SendWsError::Handshake(e.to_string()));
+        }
+    };
+    let cleanup = cleanup.into_inner();
+    let store = store.into_inner();
+    let id = claims.id;
+    actix_web::rt::spawn(async move {
+        let scan = match scanner.as_ref() {
+            Some(scanner) => Some(scanner.start(id, &info.record.filename).await),
+            None => None,
+        };
+        let relay = Relay::new(&mut info, &content_types, images.strip_metadata, scan);
+        let res = ws::relay(session, stream, relay).await;
+        if let Err(e) = &res {
+            tracing::debug!(%id, error = %e, "WebSocket transfer failed");
+        }
+        finish_transfer(&cleanup, &store, id, &info, relay::status(&res)).await;
+    });
+
+    Ok(res)
+}
+
+/// The user's paired devices, in the order they were paired.
+#[utoipa::path(
+    get,
+    path = "/devices",
+    tag = "devices",
+    responses((status = 200, body = Vec<Device>), RequireTokenError),
+    security(("token" = [])),
+)]
+#[get("")]
+async fn list_devices(devices: web::Data<Devices>, user: ReqData<User>) -> HttpResponse {
+    HttpResponse::Ok().json(devices.list(&user.0))
+}
+
+/// Creates a one-time code that pairs the device opening `/?pair=<code>`.
+#[utoipa::path(
+    post,
+    path = "/devices/pairings",
+    tag = "devices",
+    request_body = StartPairingBody,
+    responses((status = 200, body = StartPairing), DeviceError),
+    security(("token" = [])),
+)]
+#[post("/pairings")]
+async fn start_pairing(
+    devices: web::Data<Devices>,
+    cleanup: web::Data<Addr<Cleanup>>,
+    proxy: web::Data<ProxyConfig>,
+    user: ReqData<User>,
+    web::Json(body): web::Json<StartPairingBody>,
+    req: HttpRequest,
+) -> Result<web::Json<StartPairing>, DeviceError> {
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > devices::MAX_NAME_LEN {
+        return Err(DeviceError::BadName);
+    }
+
+    let pairing = devices.start_pairing(&user.0, name);
+    cleanup.do_send(TrackPairing(pairing.code.clone(), pairing.deadline));
+    let url = format!("{}/?pair={}", proxy.public_url(&req), pairing.code);
+
+    Ok(web::Json(StartPairing { pairing, url }))
+}
+
+/// Unpairs a device, its token stops working.
+#[utoipa::path(
+    delete,
+    path = "/devices/{id}",
+    tag = "devices",
+    params(("id" = Uuid, Path)),
+    responses((status = 204, description = "The device is unpaired"), DeviceError),
+    security(("token" = [])),
+)]
+#[delete("/{id}")]
+async fn remove_device(
+    devices: web::Data<Devices>,
+    user: ReqData<User>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, DeviceError> {
+    match devices.remove(&user.0, &id) {
+        true => Ok(HttpResponse::NoContent().finish()),
+        false => Err(DeviceError::NoDevice),
+    }
+}
+
+/// Pairs the device making the request.
+#[utoipa::path(
+    post,
+    path = "/device/pair",
+    tag = "devices",
+    request_body = PairDeviceBody,
+    responses(
+        (status = 200, description = "The device's token is also set as a cookie", body = PairDevice),
+        DeviceError,
+    ),
+)]
+#[post("/pair")]
+async fn pair_device(
+    devices: web::Data<Devices>,
+    proxy: web::Data<ProxyConfig>,
+    web::Json(body): web::Json<PairDeviceBody>,
+) -> Result<HttpResponse, DeviceError> {
+    let (device, token) = devices.pair(&body.code).ok_or(DeviceError::BadCode)?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(
+            CookieBuilder::new(DEVICE_COOKIE, token.clone())
+                // the device routes with and without the version
+                .path(format!("{}/api", proxy.base_path()))
+                // browsers cap cookies at 400 days
+                .expires(time::OffsetDateTime::now_utc() + time::Duration::days(400))
+                .same_site(SameSite::Strict)
+                .http_only(true)
+                .finish(),
+        )
+        .json(PairDevice { device, token }))
+}
+
+/// The device the request comes from, to check whether it's (still) paired.
+#[utoipa::path(
+    get,
+    path = "/device",
+    tag = "devices",
+    responses((status = 200, body = Device), PairedDeviceError),
+    security(("device" = [])),
+)]
+#[get("")]
+async fn current_device(PairedDevice(device): PairedDevice) -> HttpResponse {
+    HttpResponse::Ok().json(device)
+}
+
+/// Streams the transfers sent to a device, starting with the ones that are already waiting.
+///
+/// The first event is a `device` event with the device, then there's a `transfer` event with an
+/// offer for every transfer.
+#[utoipa::path(
+    get,
+    path = "/device/inbox",
+    tag = "devices",
+    responses(
+        (status = 200, description = "Server-sent events", content_type = "text/event-stream", body = Offer),
+        PairedDeviceError,
+    ),
+    security(("device" = [])),
+)]
+#[get("/inbox")]
+async fn device_inbox(
+    devices: web::Data<Devices>,
+    transfers: web::Data<Transfers>,
+    PairedDevice(device): PairedDevice,
+) -> impl Responder {
+    // subscribe first, so no offer is missed, the page ignores duplicates
+    let rx = devices.subscribe();
+    let waiting = transfers.offers_for(&device.id);
+    let id = device.id;
+    let live = futures::stream::unfold(rx, move |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(offer) if offer.device == id => return Some((offer, rx)),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let offers = futures::stream::iter(waiting).chain(live).map(|offer| {
+        sse::Data::new_json(offer).map(|data| sse::Event::Data(data.event("transfer")))
+    });
+    let events = futures::stream::once(async move {
+        sse::Data::new_json(device).map(|data| sse::Event::Data(data.event("device")))
+    })
+    .chain(offers);
+
+    sse::Sse::from_stream(events).with_keep_alive(Duration::from_secs(15))
+}
+
+/// The OpenAPI document of this API.
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = 200, description = "OpenAPI 3.1", content_type = "application/json")),
+)]
+#[get("/openapi.json")]
+async fn openapi_json(proxy: web::Data<ProxyConfig>, req: HttpRequest) -> HttpResponse {
+    let mut api = ApiDoc::openapi();
+    let url = format!("{}/api/{API_VERSION}", proxy.public_url(&req));
+    api.servers = Some(vec![Server::new(url)]);
+
+    HttpResponse::Ok().json(api)
+}
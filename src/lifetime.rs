@@ -0,0 +1,56 @@
+use serde::Deserialize;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::time::Instant;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub struct LifetimeConfig {
+    #[serde(default = "default_transfer")]
+    pub transfer: u64,
+    #[serde(default)]
+    pub max_transfer: Option<u64>,
+    #[serde(default = "default_status")]
+    pub status: u64,
+}
+
+/// This is computed once per transfer, so its token, cookie and cleanup agree.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferLifetime {
+    pub expires_at: OffsetDateTime,
+    pub deadline: Instant,
+}
+
+fn default_transfer() -> u64 {
+    10 * 60
+}
+
+fn default_status() -> u64 {
+    60
+}
+
+impl Default for LifetimeConfig {
+    fn default() -> Self {
+        Self {
+            transfer: default_transfer(),
+            max_transfer: None,
+            status: default_status(),
+        }
+    }
+}
+
+impl LifetimeConfig {
+    /// The lifetime of a new transfer, `requested` is clamped to the maximum.
+    pub fn transfer(&self, requested: Option<u64>) -> TransferLifetime {
+        let max = self.max_transfer.unwrap_or(self.transfer);
+        let ttl = Duration::from_secs(requested.unwrap_or(self.transfer).clamp(1, max.max(1)));
+        TransferLifetime {
+            expires_at: OffsetDateTime::now_utc() + ttl,
+            deadline: Instant::now() + ttl,
+        }
+    }
+
+    pub fn status(&self) -> Duration {
+        Duration::from_secs(self.status)
+    }
+}
@@ -0,0 +1,220 @@
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+pub const SERVICE_TYPE: &str = "_frachter._tcp.local.";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct MdnsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+fn default_name() -> String {
+    "Frachter".to_owned()
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: default_name(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredServer {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub version: Option<String>,
+    /// The prefix of the server's routes, e.g. `/frachter`.
+    pub path: String,
+}
+
+/// Keeps the server advertised until it's dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+/// If it listens on an unspecified address (e.g. `0.0.0.0`), the addresses of all interfaces are advertised.
+/// `path` is the prefix of all routes, e.g. `/frachter`.
+pub fn advertise(
+    config: &MdnsConfig,
+    addrs: &[SocketAddr],
+    path: &str,
+) -> Result<Advertisement, mdns_sd::Error> {
+    let port = addrs.first().map_or(0, SocketAddr::port);
+    let daemon = ServiceDaemon::new()?;
+    // only servers listening on loopback are advertised there, which is mostly useful for testing
+    if addrs.iter().any(|a| a.ip().is_loopback()) {
+        daemon.enable_interface(vec![IfKind::LoopbackV4, IfKind::LoopbackV6])?;
+    }
+    // there's no TLS certificate to pin, the server only speaks plain HTTP and TLS is up to a reverse proxy
+    let properties = [("version", env!("CARGO_PKG_VERSION")), ("path", path)];
+    let auto = addrs.iter().any(|a| a.ip().is_unspecified());
+    let ips: Vec<IpAddr> = match auto {
+        true => Vec::new(),
+        false => addrs.iter().map(SocketAddr::ip).collect(),
+    };
+    let mut service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &config.name,
+        &host_name(&config.name),
+        ips.as_slice(),
+        port,
+        properties.as_slice(),
+    )?;
+    if auto {
+        service = service.enable_addr_auto();
+    }
+    let fullname = service.get_fullname().to_owned();
+    daemon.register(service)?;
+    info!(name = %config.name, port, "Advertising on the local network");
+
+    Ok(Advertisement { daemon, fullname })
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        // tells clients that the server is gone
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            warn!(error = %e, "Couldn't stop advertising");
+        }
+        self.daemon.shutdown().ok();
+    }
+}
+
+pub async fn discover(timeout: Duration) -> Result<Vec<DiscoveredServer>, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    daemon.enable_interface(vec![IfKind::LoopbackV4, IfKind::LoopbackV6])?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+
+    // keyed by the full name, a server is resolved again for every interface it's found on
+    let mut servers = BTreeMap::new();
+    let deadline = tokio::time::Instant::now() + timeout;
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                servers.insert(
+                    info.get_fullname().to_owned(),
+                    DiscoveredServer::from(&info),
+                );
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                servers.remove(&fullname);
+            }
+            _ => (),
+        }
+    }
+    daemon.shutdown().ok();
+
+    Ok(servers.into_values().collect())
+}
+
+impl From<&ServiceInfo> for DiscoveredServer {
+    fn from(info: &ServiceInfo) -> Self {
+        let name = info
+            .get_fullname()
+            .strip_suffix(SERVICE_TYPE)
+            .and_then(|name| name.strip_suffix('.'))
+            .unwrap_or(info.get_fullname())
+            .to_owned();
+        let mut addresses: Vec<_> = info.get_addresses().iter().copied().collect();
+        addresses.sort();
+
+        Self {
+            name,
+            addresses,
+            port: info.get_port(),
+            version: info.get_property_val_str("version").map(str::to_owned),
+            path: info
+                .get_property_val_str("path")
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+}
+
+impl DiscoveredServer {
+    /// The URLs the server might be reachable at, IPv4 first.
+    pub fn urls(&self) -> Vec<String> {
+        self.addresses
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.port))
+            .map(|addr| format!("http://{addr}{}/", self.path))
+            .collect()
+    }
+}
+
+fn host_name(name: &str) -> String {
+    let host: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect();
+    match host.trim_matches('-') {
+        "" => "frachter.local.".to_owned(),
+        host => format!("{host}.local."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_discovered_services() {
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "Living Room",
+            &host_name("Living Room"),
+            "192.168.1.20,10.0.0.5",
+            8080,
+            [("version", "1.2.3"), ("path", "/frachter")].as_slice(),
+        )
+        .unwrap();
+
+        let server = DiscoveredServer::from(&info);
+        assert_eq!(server.name, "Living Room");
+        assert_eq!(server.version.as_deref(), Some("1.2.3"));
+        assert_eq!(
+            server.urls(),
+            [
+                "http://10.0.0.5:8080/frachter/",
+                "http://192.168.1.20:8080/frachter/"
+            ]
+        );
+        assert_eq!(host_name("Living Room"), "living-room.local.");
+        assert_eq!(host_name("???"), "frachter.local.");
+    }
+
+    /// Run with `--ignored` once loopback has multicast, on Linux e.g. `ip link set lo multicast on`.
+    #[tokio::test]
+    #[ignore = "needs multicast on loopback"]
+    async fn discovers_advertised_servers() {
+        let config = MdnsConfig {
+            enabled: true,
+            name: "Test Server".to_owned(),
+        };
+        let _advertisement = advertise(&config, &["127.0.0.1:18080".parse().unwrap()], "").unwrap();
+
+        let servers = discover(Duration::from_secs(3)).await.unwrap();
+        let server = servers.iter().find(|s| s.name == "Test Server").unwrap();
+        assert_eq!(server.port, 18080);
+        assert!(server.addresses.contains(&"127.0.0.1".parse().unwrap()));
+        assert_eq!(server.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    cleanup::{Cleanup, GetMetrics},
+    status::{FailureReason, TransferStatus},
+};
+use actix::Addr;
+use actix_web::{get, http::header::ContentType, web, HttpResponse};
+use std::{collections::BTreeMap, fmt::Write};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    pub created: u64,
+    pub completed: u64,
+    /// Declines are on purpose, so they're not counted as failures.
+    pub declined: u64,
+    pub failed: BTreeMap<FailureReason, u64>,
+}
+
+impl Metrics {
+    pub fn finished(&mut self, status: &TransferStatus) {
+        match status {
+            TransferStatus::Completed { .. } => self.completed += 1,
+            TransferStatus::Failed {
+                reason: FailureReason::ReceiverDeclined,
+                ..
+            } => self.declined += 1,
+            TransferStatus::Failed { reason, .. } => *self.failed.entry(*reason).or_default() += 1,
+        }
+    }
+
+    pub fn render(&self, open: usize) -> String {
+        let failed = self
+            .failed
+            .iter()
+            .map(|(reason, count)| {
+                let reason = serde_json::to_value(reason).unwrap_or_default();
+                (format!("{{reason={reason}}}"), *count)
+            })
+            .collect();
+        let mut out = String::new();
+        for (name, kind, help, samples) in [
+            (
+                "frachter_transfers_created_total",
+                "counter",
+                "Transfers created since the server started",
+                vec![(String::new(), self.created)],
+            ),
+            (
+                "frachter_transfers_completed_total",
+                "counter",
+                "Transfers that completed since the server started",
+                vec![(String::new(), self.completed)],
+            ),
+            (
+                "frachter_transfers_declined_total",
+                "counter",
+                "Transfers the receiver declined since the server started",
+                vec![(String::new(), self.declined)],
+            ),
+            (
+                "frachter_transfers_failed_total",
+                "counter",
+                "Transfers that failed or expired since the server started, by reason",
+                failed,
+            ),
+            (
+                "frachter_transfers_open",
+                "gauge",
+                "Transfers that are waiting for a receiver or being sent",
+                vec![(String::new(), open as u64)],
+            ),
+        ] {
+            // writing to a string can't fail
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+pub enum MetricsError {
+    #[error("Couldn't reach cleanup")]
+    #[status(500)]
+    Actix,
+}
+
+/// The metrics in the Prometheus text format.
+#[get("/metrics")]
+pub async fn get_metrics(cleanup: web::Data<Addr<Cleanup>>) -> Result<HttpResponse, MetricsError> {
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentType(
+            "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+        ))
+        .body(
+            cleanup
+                .send(GetMetrics)
+                .await
+                .map_err(|_| MetricsError::Actix)?,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::FailureReason;
+
+    #[test]
+    fn renders_the_prometheus_text_format() {
+        let mut metrics = Metrics {
+            created: 3,
+            ..Default::default()
+        };
+        metrics.finished(&TransferStatus::Completed {
+            digest: None,
+            scan: None,
+        });
+        metrics.finished(&TransferStatus::failed(FailureReason::Expired));
+        metrics.finished(&TransferStatus::failed(FailureReason::ReceiverDisconnected));
+        metrics.finished(&TransferStatus::declined(Some("wrong file".to_owned())));
+
+        let text = metrics.render(1);
+        assert!(text.contains("# TYPE frachter_transfers_created_total counter\n"));
+        assert!(text.contains("\nfrachter_transfers_created_total 3\n"));
+        assert!(text.contains("\nfrachter_transfers_completed_total 1\n"));
+        assert!(text.contains("\nfrachter_transfers_declined_total 1\n"));
+        assert!(text.contains("\nfrachter_transfers_failed_total{reason=\"expired\"} 1\n"));
+        assert!(
+            text.contains("\nfrachter_transfers_failed_total{reason=\"receiverDisconnected\"} 1\n")
+        );
+        assert!(!text.contains("receiverDeclined"));
+        assert!(text.contains("\nfrachter_transfers_open 1\n"));
+    }
+}
@@ -1,19 +1,46 @@
-use crate::{jwt, Service};
-use actix_web::dev::{ServiceRequest, ServiceResponse, Transform};
+use crate::{
+    jwt, openapi,
+    proxy::{ProxyConfig, FORWARDED_HEADERS},
+    Service,
+};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    HttpMessage,
+};
 use futures::future::Either;
 use std::{
+    collections::HashMap,
     future::{ready, Ready},
     sync::Arc,
 };
-use actix_web::HttpMessage;
 
-pub struct RequireToken(pub Arc<String>);
+/// Maps tokens to the names of their users.
+pub type Tokens = HashMap<String, String>;
+
+pub struct RequireToken(pub Arc<Tokens>);
+
+/// The user that authenticated the request with their token.
+#[derive(Debug, Clone)]
+pub struct User(pub String);
 
 pub struct JwtDecoder(pub Arc<jwt::DecodeConfig>);
 
+/// Replaces the `Forwarded` and `X-Forwarded-*` headers with what the trusted proxies forwarded.
+///
+/// Has to wrap everything that looks at the connection info, like the logger.
+pub struct TrustProxies(pub Arc<ProxyConfig>);
+
+/// Rejects requests whose `Origin` isn't the one the app is reachable at.
+///
+/// Routes that are only authenticated with a cookie need this, browsers send cookies along with
+/// cross-origin WebSocket and `EventSource` requests. Requests without an `Origin` don't come
+/// from a browser's script and are let through.
+pub struct SameOrigin(pub Arc<ProxyConfig>);
+
 pub struct RequireTokenMiddleware<S> {
     service: S,
-    token: Arc<String>,
+    tokens: Arc<Tokens>,
 }
 
 pub struct JwtDecoderMiddleware<S> {
@@ -21,6 +48,16 @@ pub struct JwtDecoderMiddleware<S> {
     config: Arc<jwt::DecodeConfig>,
 }
 
+pub struct TrustProxiesMiddleware<S> {
+    service: S,
+    config: Arc<ProxyConfig>,
+}
+
+pub struct SameOriginMiddleware<S> {
+    service: S,
+    config: Arc<ProxyConfig>,
+}
+
 #[derive(Debug, thiserror::Error, actix_web_error::Json)]
 #[status(401)]
 pub enum RequireTokenError {
@@ -30,6 +67,11 @@ pub enum RequireTokenError {
     BadToken,
 }
 
+openapi::error_responses!(
+    RequireTokenError,
+    [RequireTokenError::NoToken, RequireTokenError::BadToken]
+);
+
 #[derive(Debug, thiserror::Error, actix_web_error::Json)]
 #[status(401)]
 pub enum JwtDecodeError {
@@ -39,6 +81,13 @@ pub enum JwtDecodeError {
     BadToken,
 }
 
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+#[status(403)]
+pub enum OriginError {
+    #[error("Requests from other origins aren't allowed")]
+    CrossOrigin,
+}
+
 impl<S, B> Transform<S, ServiceRequest> for RequireToken
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
@@ -54,7 +103,7 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(Self::Transform {
             service,
-            token: self.0.clone(),
+            tokens: self.0.clone(),
         }))
     }
 }
@@ -77,11 +126,13 @@ where
             Some(h) => h,
             None => return Either::Left(ready(Err(RequireTokenError::NoToken.into()))),
         };
-        if header.as_bytes() == self.token.as_bytes() {
-            Either::Right(self.service.call(req))
-        } else {
-            Either::Left(ready(Err(RequireTokenError::BadToken.into())))
-        }
+        let user = match header.to_str().ok().and_then(|t| self.tokens.get(t)) {
+            Some(user) => User(user.clone()),
+            None => return Either::Left(ready(Err(RequireTokenError::BadToken.into()))),
+        };
+        req.extensions_mut().insert(user);
+
+        Either::Right(self.service.call(req))
     }
 }
 
@@ -132,3 +183,96 @@ where
         Either::Right(self.service.call(req))
     }
 }
+
+impl<S, B> Transform<S, ServiceRequest> for TrustProxies
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = TrustProxiesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TrustProxiesMiddleware {
+            service,
+            config: self.0.clone(),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for TrustProxiesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = S::Future;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let forwarded = self.config.forwarded(req.headers(), peer);
+        let headers = req.headers_mut();
+        for name in &FORWARDED_HEADERS {
+            headers.remove(name);
+        }
+        if let Some(forwarded) = forwarded {
+            headers.insert(header::FORWARDED, forwarded);
+        }
+
+        self.service.call(req)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SameOrigin
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = SameOriginMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SameOriginMiddleware {
+            service,
+            config: self.0.clone(),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for SameOriginMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Either<Ready<Result<Self::Response, Self::Error>>, S::Future>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(origin) = req.headers().get(header::ORIGIN) {
+            let same = origin
+                .to_str()
+                .is_ok_and(|origin| self.config.is_same_origin(req.request(), origin));
+            if !same {
+                return Either::Left(ready(Err(OriginError::CrossOrigin.into())));
+            }
+        }
+
+        Either::Right(self.service.call(req))
+    }
+}
@@ -0,0 +1,172 @@
+use actix_web::ResponseError;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        ContentBuilder, RefOr, Response, ResponseBuilder,
+    },
+    Modify, OpenApi, PartialSchema, ToSchema,
+};
+
+/// The current version of the API, its routes are served under `/api/v1`.
+///
+/// Within a version, endpoints, fields and enum values are only ever added, clients have to ignore
+/// the ones they don't know. Anything else is a new version, the old one is kept for a while.
+pub const API_VERSION: &str = "v1";
+
+/// The body of every error response.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// A message for humans, don't match on it.
+    pub error: String,
+}
+
+/// The responses of an endpoint's errors, built from `examples` so the statuses can't drift.
+pub fn describe_errors(examples: &[&dyn ResponseError]) -> BTreeMap<String, RefOr<Response>> {
+    let mut messages = BTreeMap::<_, Vec<_>>::new();
+    for example in examples {
+        messages
+            .entry(example.status_code().as_u16().to_string())
+            .or_default()
+            .push(format!("* {example}"));
+    }
+    messages
+        .into_iter()
+        .map(|(status, messages)| {
+            let response = ResponseBuilder::new()
+                .description(messages.join("\n"))
+                .content(
+                    "application/json",
+                    ContentBuilder::new()
+                        .schema(Some(ErrorBody::schema()))
+                        .build(),
+                )
+                .build();
+            (status, response.into())
+        })
+        .collect()
+}
+
+/// Implements [`utoipa::IntoResponses`] for an error type with [`describe_errors`],
+/// the examples can include errors of the extractors the endpoints use.
+macro_rules! error_responses {
+    ($error:ty, [$($example:expr),+ $(,)?]) => {
+        impl utoipa::IntoResponses for $error {
+            fn responses() -> std::collections::BTreeMap<
+                String,
+                utoipa::openapi::RefOr<utoipa::openapi::Response>,
+            > {
+                $crate::openapi::describe_errors(&[$(&$example),+])
+            }
+        }
+    };
+}
+pub(crate) use error_responses;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "x-frachter-token",
+                "The token of a user from the config",
+            ))),
+        );
+        components.add_security_scheme(
+            "transfer",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                "frachter-transfer",
+                "Set when creating a transfer",
+            ))),
+        );
+        components.add_security_scheme(
+            "device",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                crate::devices::DEVICE_COOKIE,
+                "Set when pairing a device, clients without cookies send it as `x-frachter-device`",
+            ))),
+        );
+    }
+}
+
+/// The OpenAPI document of the API, served at `/api/v1/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Frachter",
+        description = "Relays files from senders to receivers without storing them. \
+            Routes are relative to `/api/v1`, `/api` is an alias for existing clients."
+    ),
+    paths(
+        crate::create_transfer,
+        crate::wait_transfer,
+        crate::transfer_events,
+        crate::cancel_transfer,
+        crate::send,
+        crate::send_ws,
+        crate::transfer_status,
+        crate::upload_thumbnail,
+        crate::receive,
+        crate::view,
+        crate::receive_info,
+        crate::receive_status,
+        crate::decline_transfer,
+        crate::get_thumbnail,
+        crate::list_devices,
+        crate::start_pairing,
+        crate::remove_device,
+        crate::current_device,
+        crate::pair_device,
+        crate::device_inbox,
+        crate::get_history,
+        crate::openapi_json,
+    ),
+    components(schemas(crate::progress::TransferProgress, crate::devices::Offer)),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "transfers", description = "Sending files"),
+        (name = "receive", description = "Receiving files, the transfer's id is all it takes"),
+        (name = "devices", description = "Devices paired with a user get transfers without scanning a QR code"),
+        (name = "history", description = "Finished transfers, if the server has a database"),
+    )
+)]
+pub struct ApiDoc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn documents_routes_and_errors() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let transfers = &doc["paths"]["/transfers"]["put"];
+        assert_eq!(
+            transfers["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/CreateTransferBody"
+        );
+        for status in ["200", "400", "401", "413", "429", "500"] {
+            assert!(transfers["responses"].get(status).is_some(), "{status}");
+        }
+        let description = transfers["responses"]["429"]["description"]
+            .as_str()
+            .unwrap();
+        assert!(description.contains("* Too many open transfers on this server"));
+
+        let status = &doc["paths"]["/receive/{id}/status"]["get"];
+        assert!(status["responses"]["404"].is_object());
+        assert!(status.get("security").is_none());
+        assert_eq!(
+            doc["components"]["securitySchemes"]["token"]["name"],
+            "x-frachter-token"
+        );
+        assert!(matches!(
+            &doc["components"]["schemas"]["TransferProgress"],
+            Value::Object(_)
+        ));
+    }
+}
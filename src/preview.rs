@@ -0,0 +1,169 @@
+use crate::images::ImageDetails;
+use actix_web::{http::header, HttpResponse, HttpResponseBuilder};
+use uuid::Uuid;
+
+/// Applied to payloads shown inline, nothing they contain may load or run anything.
+const PAYLOAD_CSP: &str =
+    "default-src 'none'; img-src 'self'; media-src 'self'; style-src 'unsafe-inline'";
+
+/// Applied to inline PDFs instead, the browser's PDF viewer is a plugin that `object-src` has to allow.
+const PDF_CSP: &str =
+    "default-src 'none'; object-src 'self'; img-src 'self'; style-src 'unsafe-inline'";
+
+/// Applied to the viewer page, it only embeds the payload from this origin.
+const VIEWER_CSP: &str = "default-src 'none'; img-src 'self'; media-src 'self'; frame-src 'self'; object-src 'self'; style-src 'unsafe-inline'; form-action 'none'; base-uri 'none'; frame-ancestors 'none'";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Viewer {
+    Image,
+    Video,
+    Audio,
+    Pdf,
+    Text,
+}
+
+impl Viewer {
+    /// The viewer for `mime`, or `None` if it can't be shown inline safely.
+    /// Types that can run scripts (e.g. HTML or SVG) are always downloaded.
+    pub fn for_mime(mime: &mime::Mime) -> Option<Self> {
+        match (mime.type_(), mime.subtype().as_str()) {
+            (mime::IMAGE, "png" | "jpeg" | "gif" | "webp" | "avif" | "bmp") => Some(Self::Image),
+            (mime::VIDEO, "mp4" | "webm" | "ogg") => Some(Self::Video),
+            (mime::AUDIO, "mpeg" | "mp4" | "ogg" | "wav" | "webm" | "flac" | "aac") => {
+                Some(Self::Audio)
+            }
+            (mime::APPLICATION, "pdf") => Some(Self::Pdf),
+            (mime::TEXT, "plain" | "csv" | "markdown") => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    fn csp(self) -> &'static str {
+        match self {
+            Self::Pdf => PDF_CSP,
+            _ => PAYLOAD_CSP,
+        }
+    }
+}
+
+pub fn lock_down(res: &mut HttpResponseBuilder, viewer: Viewer) {
+    res.insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((header::CONTENT_SECURITY_POLICY, viewer.csp()));
+}
+
+/// If the image's `details` are known, its thumbnail is shown until it's loaded.
+pub fn viewer_page(
+    id: Uuid,
+    filename: &str,
+    viewer: Viewer,
+    details: Option<ImageDetails>,
+) -> HttpResponse {
+    let src = format!("../{id}?preview=true");
+    let embed = match (viewer, details) {
+        (Viewer::Image, Some(_)) => format!(
+            r#"<img src="{src}" alt="" style="background: url(../{id}/thumbnail) center / contain no-repeat">"#
+        ),
+        (Viewer::Image, None) => format!(r#"<img src="{src}" alt="">"#),
+        (Viewer::Video, _) => format!(r#"<video src="{src}" controls autoplay></video>"#),
+        (Viewer::Audio, _) => format!(r#"<audio src="{src}" controls autoplay></audio>"#),
+        (Viewer::Pdf, _) => format!(r#"<object data="{src}" type="application/pdf"></object>"#),
+        (Viewer::Text, _) => format!(r#"<iframe src="{src}" sandbox></iframe>"#),
+    };
+    let title = escape_html(filename);
+    let details = details.map_or_else(String::new, |d| {
+        format!(
+            "<p>{} × {} · {}</p>",
+            d.width,
+            d.height,
+            format_size(d.size)
+        )
+    });
+
+    HttpResponse::Ok()
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((header::CONTENT_SECURITY_POLICY, VIEWER_CSP))
+        .insert_header((header::REFERRER_POLICY, "no-referrer"))
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <title>{title}</title>
+    <style>
+        body {{ margin: 0; background: #121212; color: #eee; font-family: sans-serif; display: flex; flex-direction: column; height: 100vh; }}
+        h1 {{ font-size: 1rem; margin: .5rem; overflow-wrap: anywhere; }}
+        p {{ font-size: .8rem; margin: 0 .5rem .5rem; opacity: .7; }}
+        img, video, audio, object, iframe {{ flex: 1; min-height: 0; max-width: 100%; object-fit: contain; border: none; }}
+        iframe {{ background: #fff; }}
+    </style>
+</head>
+<body>
+<h1>{title}</h1>
+{details}
+{embed}
+</body>
+</html>
+"#
+        ))
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_previews_safe_types() {
+        assert_eq!(Viewer::for_mime(&mime::IMAGE_JPEG), Some(Viewer::Image));
+        assert_eq!(
+            Viewer::for_mime(&mime::TEXT_PLAIN_UTF_8),
+            Some(Viewer::Text)
+        );
+        assert_eq!(Viewer::for_mime(&mime::APPLICATION_PDF), Some(Viewer::Pdf));
+        assert_eq!(Viewer::for_mime(&mime::IMAGE_SVG), None);
+        assert_eq!(Viewer::for_mime(&mime::TEXT_HTML), None);
+        assert_eq!(
+            Viewer::for_mime(&"application/xhtml+xml".parse().unwrap()),
+            None
+        );
+        assert_eq!(Viewer::for_mime(&mime::APPLICATION_OCTET_STREAM), None);
+    }
+
+    #[test]
+    fn escapes_filenames() {
+        assert_eq!(
+            escape_html(r#"<script>"a" & 'b'</script>"#),
+            "&lt;script&gt;&quot;a&quot; &amp; &#39;b&#39;&lt;/script&gt;"
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use actix_web::body::{BodySize, MessageBody};
+use bytes::Bytes;
+use serde::Serialize;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::watch;
+use utoipa::ToSchema;
+
+/// The state of a transfer as seen by the sender.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TransferProgress {
+    Waiting,
+    ReceiverConnected,
+    Delivered {
+        bytes: u64,
+    },
+    ReceiverDisconnected {
+        bytes: u64,
+    },
+    /// The receiver declined the transfer, before or while receiving it.
+    ReceiverDeclined {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    Completed {
+        bytes: u64,
+    },
+}
+
+impl TransferProgress {
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            Self::Waiting => "waiting",
+            Self::ReceiverConnected => "receiverConnected",
+            Self::Delivered { .. } => "delivered",
+            Self::ReceiverDisconnected { .. } => "receiverDisconnected",
+            Self::ReceiverDeclined { .. } => "receiverDeclined",
+            Self::Completed { .. } => "completed",
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            Self::ReceiverDisconnected { .. }
+                | Self::ReceiverDeclined { .. }
+                | Self::Completed { .. }
+        )
+    }
+}
+
+pub type ProgressTx = watch::Sender<TransferProgress>;
+pub type ProgressRx = watch::Receiver<TransferProgress>;
+
+pub fn channel() -> (ProgressTx, ProgressRx) {
+    watch::channel(TransferProgress::Waiting)
+}
+
+/// A body that reports how many bytes were consumed by the receiver.
+pub struct ProgressBody<B> {
+    body: B,
+    tx: ProgressTx,
+    bytes: u64,
+    done: bool,
+}
+
+impl<B> ProgressBody<B> {
+    pub fn new(body: B, tx: ProgressTx) -> Self {
+        Self {
+            body,
+            tx,
+            bytes: 0,
+            done: false,
+        }
+    }
+}
+
+impl<B> ProgressBody<B> {
+    /// A final state, e.g. after the receiver declined, isn't replaced by the body's.
+    fn update(&self, progress: TransferProgress) {
+        self.tx
+            .send_if_modified(|current| match current.is_final() {
+                true => false,
+                false => {
+                    *current = progress;
+                    true
+                }
+            });
+    }
+}
+
+impl<B> MessageBody for ProgressBody<B>
+where
+    B: MessageBody + Unpin,
+{
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.body).poll_next(cx);
+        match &res {
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.bytes += chunk.len() as u64;
+                this.update(TransferProgress::Delivered { bytes: this.bytes });
+            }
+            Poll::Ready(None) => {
+                this.done = true;
+                this.update(TransferProgress::Completed { bytes: this.bytes });
+            }
+            _ => (),
+        }
+        res
+    }
+}
+
+impl<B> Drop for ProgressBody<B> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.update(TransferProgress::ReceiverDisconnected { bytes: self.bytes });
+        }
+    }
+}
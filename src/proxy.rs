@@ -0,0 +1,370 @@
+use actix_web::{
+    http::header::{self, HeaderMap, HeaderName, HeaderValue},
+    HttpRequest,
+};
+use serde::Deserialize;
+use std::{fmt, net::IpAddr, str::FromStr};
+use url::Url;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// The headers proxies tell the server about the client with.
+pub const FORWARDED_HEADERS: [HeaderName; 4] = [
+    header::FORWARDED,
+    X_FORWARDED_FOR,
+    X_FORWARDED_PROTO,
+    X_FORWARDED_HOST,
+];
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProxyConfig {
+    #[serde(default)]
+    pub base_url: Option<Url>,
+    #[serde(default)]
+    pub base_path: Option<String>,
+    #[serde(default)]
+    pub trusted: Vec<Network>,
+}
+
+impl ProxyConfig {
+    /// The prefix of all routes without a trailing slash, e.g. `/frachter`, or an empty string.
+    pub fn base_path(&self) -> String {
+        let path = match (&self.base_path, &self.base_url) {
+            (Some(path), _) => path.as_str(),
+            (None, Some(url)) => url.path(),
+            (None, None) => "",
+        };
+        match path.trim_matches('/') {
+            "" => String::new(),
+            path => format!("/{path}"),
+        }
+    }
+
+    /// The URL the app is reachable at without a trailing slash, `base_url` or the one `req` was made to.
+    pub fn public_url(&self, req: &HttpRequest) -> String {
+        match &self.base_url {
+            Some(url) => url.as_str().trim_end_matches('/').to_owned(),
+            None => {
+                let info = req.connection_info();
+                format!("{}://{}{}", info.scheme(), info.host(), self.base_path())
+            }
+        }
+    }
+
+    pub fn is_same_origin(&self, req: &HttpRequest, origin: &str) -> bool {
+        Url::parse(&self.public_url(req))
+            .is_ok_and(|url| url.origin().ascii_serialization() == origin)
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|network| network.contains(ip))
+    }
+
+    /// A single `Forwarded` header replacing the ones of the request, `None` if they aren't trusted.
+    /// Requests without a peer address come from a unix socket and are trusted.
+    /// The client is the last address in the chain that isn't a trusted proxy.
+    pub fn forwarded(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<HeaderValue> {
+        if peer.is_some_and(|ip| !self.trusts(ip)) {
+            return None;
+        }
+        let hops = hops(headers);
+        let hop = hops
+            .iter()
+            .rev()
+            .find(|hop| {
+                hop.client
+                    .as_deref()
+                    .and_then(parse_node)
+                    .is_none_or(|ip| !self.trusts(ip))
+            })
+            .or(hops.first())?;
+
+        let mut params = Vec::new();
+        if let Some(client) = &hop.client {
+            params.push(format!("for={}", quote(client)));
+        }
+        if let Some(proto) = &hop.proto {
+            params.push(format!("proto={}", quote(proto)));
+        }
+        if let Some(host) = &hop.host {
+            params.push(format!("host={}", quote(host)));
+        }
+        HeaderValue::from_str(&params.join(";")).ok()
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Hop {
+    client: Option<String>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// `Forwarded` is preferred, `X-Forwarded-Proto` and `-Host` are set by the last proxy that appended them.
+fn hops(headers: &HeaderMap) -> Vec<Hop> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    };
+    let forwarded: Vec<Hop> = values(header::FORWARDED)
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                let Some((name, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                let value = Some(value.trim().trim_matches('"').to_owned());
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "for" => hop.client = value,
+                    "proto" => hop.proto = value,
+                    "host" => hop.host = value,
+                    _ => (),
+                }
+            }
+            hop
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    let mut hops: Vec<Hop> = values(X_FORWARDED_FOR)
+        .map(|client| Hop {
+            client: Some(client.to_owned()),
+            ..Hop::default()
+        })
+        .collect();
+    let proto = values(X_FORWARDED_PROTO).last().map(str::to_owned);
+    let host = values(X_FORWARDED_HOST).last().map(str::to_owned);
+    if hops.is_empty() && (proto.is_some() || host.is_some()) {
+        hops.push(Hop::default());
+    }
+    for hop in &mut hops {
+        (hop.proto, hop.host) = (proto.clone(), host.clone());
+    }
+    hops
+}
+
+/// The IP of a node like `192.0.2.1`, `192.0.2.1:4711` or `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    match node.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?.parse().ok(),
+        None => node.split(':').next()?.parse().ok(),
+    }
+}
+
+fn quote(value: &str) -> String {
+    // IPv6 addresses need brackets to be told apart from ports
+    let value = match value.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]"),
+        _ => value.to_owned(),
+    };
+    format!("\"{}\"", value.replace(['"', '\\'], ""))
+}
+
+/// An address or a network in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Network {
+    ip: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // peers on dual-stack sockets show up as IPv4-mapped IPv6 addresses
+        let (network, ip, width) = match (self.ip, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (
+                u128::from(u32::from(network)),
+                u128::from(u32::from(ip)),
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        let host_bits = width - u32::from(self.prefix);
+        network.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| format!("{s:?} isn't an address or a network"))?;
+        let max = match ip {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("bad prefix length in {s:?}"))?,
+            None => max,
+        };
+        Ok(Self { ip, prefix })
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn config(trusted: &[&str]) -> ProxyConfig {
+        ProxyConfig {
+            trusted: trusted.iter().map(|n| n.parse().unwrap()).collect(),
+            ..ProxyConfig::default()
+        }
+    }
+
+    fn forwarded(
+        config: &ProxyConfig,
+        peer: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> Option<String> {
+        let mut req = TestRequest::default();
+        for header in headers {
+            req = req.append_header(*header);
+        }
+        let req = req.to_http_request();
+        config
+            .forwarded(req.headers(), peer.map(|p| p.parse().unwrap()))
+            .map(|v| v.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn matches_networks() {
+        let network: Network = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+        let network: Network = "fd00::/8".parse().unwrap();
+        assert!(network.contains("fd12::1".parse().unwrap()));
+        assert!(!network.contains("fe80::1".parse().unwrap()));
+        let single: Network = "::1".parse().unwrap();
+        assert_eq!(single.to_string(), "::1/128");
+        assert!("0.0.0.0/0"
+            .parse::<Network>()
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn only_trusts_forwarded_headers_of_proxies() {
+        let config = config(&["10.0.0.0/8"]);
+        let spoofed = [("x-forwarded-for", "1.2.3.4")];
+        assert_eq!(forwarded(&config, Some("192.0.2.1"), &spoofed), None);
+        assert_eq!(
+            forwarded(&config, Some("10.0.0.1"), &spoofed).as_deref(),
+            Some("for=\"1.2.3.4\"")
+        );
+        // unix sockets
+        assert!(forwarded(&config, None, &spoofed).is_some());
+
+        // the client can prepend whatever it likes, the chain is followed up to the first untrusted hop
+        let chain = [
+            ("x-forwarded-for", "6.6.6.6, 192.0.2.7, 10.0.0.2"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.com"),
+        ];
+        assert_eq!(
+            forwarded(&config, Some("10.0.0.1"), &chain).as_deref(),
+            Some("for=\"192.0.2.7\";proto=\"https\";host=\"example.com\"")
+        );
+        let chain = [
+            (
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https;host=example.com",
+            ),
+            ("forwarded", "for=10.0.0.2;proto=http"),
+        ];
+        assert_eq!(
+            forwarded(&config, Some("10.0.0.1"), &chain).as_deref(),
+            Some("for=\"[2001:db8::1]:4711\";proto=\"https\";host=\"example.com\"")
+        );
+        assert_eq!(
+            forwarded(&config, Some("10.0.0.1"), &[("x-forwarded-proto", "https")]).as_deref(),
+            Some("proto=\"https\"")
+        );
+        assert_eq!(forwarded(&config, Some("10.0.0.1"), &[]), None);
+    }
+
+    #[actix_web::test]
+    async fn replaces_untrusted_headers() {
+        use crate::middleware::TrustProxies;
+        use actix_web::{test, web, App};
+        use std::sync::Arc;
+
+        let app = test::init_service(
+            App::new()
+                .wrap(TrustProxies(Arc::new(config(&["10.0.0.1"]))))
+                .route(
+                    "/",
+                    web::get().to(|req: HttpRequest| async move {
+                        let info = req.connection_info();
+                        format!("{} {}", info.realip_remote_addr().unwrap(), info.scheme())
+                    }),
+                ),
+        )
+        .await;
+        let get = |peer: &str| {
+            test::TestRequest::get()
+                .uri("/")
+                .peer_addr(format!("{peer}:4711").parse().unwrap())
+                .insert_header(("x-forwarded-for", "1.2.3.4"))
+                .insert_header(("forwarded", "for=5.6.7.8;proto=https"))
+                .to_request()
+        };
+        let body = test::call_and_read_body(&app, get("192.0.2.1")).await;
+        assert_eq!(body, "192.0.2.1 http");
+        let body = test::call_and_read_body(&app, get("10.0.0.1")).await;
+        assert_eq!(body, "5.6.7.8 https");
+    }
+
+    #[test]
+    fn normalizes_base_paths() {
+        let mut config = ProxyConfig::default();
+        assert_eq!(config.base_path(), "");
+        config.base_url = Some("https://example.com/frachter/".parse().unwrap());
+        assert_eq!(config.base_path(), "/frachter");
+        config.base_path = Some("/".to_owned());
+        assert_eq!(config.base_path(), "");
+        config.base_path = Some("apps/frachter".to_owned());
+        assert_eq!(config.base_path(), "/apps/frachter");
+    }
+}
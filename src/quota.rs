@@ -0,0 +1,185 @@
+use crate::mutex::MutexExt;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    #[serde(default)]
+    pub max_transfers_per_user: Option<usize>,
+    #[serde(default)]
+    pub daily_bytes_per_user: Option<u64>,
+    #[serde(default)]
+    pub max_transfers: Option<usize>,
+}
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+pub enum QuotaError {
+    #[error("The file is larger than the maximum of {0} bytes")]
+    #[status(413)]
+    FileTooLarge(u64),
+    #[error("Too many open transfers for this user, the maximum is {0}")]
+    #[status(429)]
+    TooManyUserTransfers(usize),
+    #[error("Too many open transfers on this server, try again later")]
+    #[status(429)]
+    TooManyTransfers,
+    #[error("The daily budget of {0} bytes is used up")]
+    #[status(429)]
+    DailyBudgetExceeded(u64),
+}
+
+#[derive(Clone)]
+pub struct Quotas {
+    config: Arc<QuotaConfig>,
+    state: Arc<StdMutex<QuotaState>>,
+}
+
+#[derive(Default)]
+struct QuotaState {
+    open: usize,
+    users: HashMap<String, UserUsage>,
+}
+
+#[derive(Default)]
+struct UserUsage {
+    open: usize,
+    day: Option<time::Date>,
+    bytes: u64,
+}
+
+/// An open transfer counted against the quotas, released when dropped.
+pub struct QuotaPermit {
+    quotas: Quotas,
+    user: String,
+    bytes: u64,
+}
+
+impl UserUsage {
+    fn bytes_today(&mut self) -> &mut u64 {
+        let today = time::OffsetDateTime::now_utc().date();
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.bytes = 0;
+        }
+        &mut self.bytes
+    }
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            state: Default::default(),
+        }
+    }
+
+    pub fn acquire(&self, user: &str) -> Result<QuotaPermit, QuotaError> {
+        let mut state = self.state.always_lock();
+        if matches!(self.config.max_transfers, Some(max) if state.open >= max) {
+            return Err(QuotaError::TooManyTransfers);
+        }
+        let usage = state.users.entry(user.to_owned()).or_default();
+        if let Some(max) = self.config.max_transfers_per_user {
+            if usage.open >= max {
+                return Err(QuotaError::TooManyUserTransfers(max));
+            }
+        }
+        if let Some(budget) = self.config.daily_bytes_per_user {
+            if *usage.bytes_today() >= budget {
+                return Err(QuotaError::DailyBudgetExceeded(budget));
+            }
+        }
+        usage.open += 1;
+        state.open += 1;
+
+        Ok(QuotaPermit {
+            quotas: self.clone(),
+            user: user.to_owned(),
+            bytes: 0,
+        })
+    }
+
+    /// For transfers that were accepted before a restart.
+    pub fn acquire_unchecked(&self, user: &str) -> QuotaPermit {
+        let mut state = self.state.always_lock();
+        state.users.entry(user.to_owned()).or_default().open += 1;
+        state.open += 1;
+
+        QuotaPermit {
+            quotas: self.clone(),
+            user: user.to_owned(),
+            bytes: 0,
+        }
+    }
+}
+
+impl QuotaPermit {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Checks the size declared by the sender before any data is sent.
+    pub fn check_size(&self, size: u64) -> Result<(), QuotaError> {
+        let config = &self.quotas.config;
+        if let Some(max) = config.max_file_size {
+            if size > max {
+                return Err(QuotaError::FileTooLarge(max));
+            }
+        }
+        if let Some(budget) = config.daily_bytes_per_user {
+            let mut state = self.quotas.state.always_lock();
+            let usage = state.users.entry(self.user.clone()).or_default();
+            if *usage.bytes_today() + size > budget {
+                return Err(QuotaError::DailyBudgetExceeded(budget));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn consume(&mut self, len: usize) -> Result<(), QuotaError> {
+        let config = &self.quotas.config;
+        self.bytes += len as u64;
+        if let Some(max) = config.max_file_size {
+            if self.bytes > max {
+                return Err(QuotaError::FileTooLarge(max));
+            }
+        }
+        let mut state = self.quotas.state.always_lock();
+        let today = state
+            .users
+            .entry(self.user.clone())
+            .or_default()
+            .bytes_today();
+        *today += len as u64;
+        match config.daily_bytes_per_user {
+            Some(budget) if *today > budget => Err(QuotaError::DailyBudgetExceeded(budget)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        let mut state = self.quotas.state.always_lock();
+        state.open = state.open.saturating_sub(1);
+        if let Some(usage) = state.users.get_mut(&self.user) {
+            usage.open = usage.open.saturating_sub(1);
+        }
+    }
+}
+
+impl fmt::Debug for QuotaPermit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuotaPermit")
+            .field("user", &self.user)
+            .field("bytes", &self.bytes)
+            .finish()
+    }
+}
@@ -0,0 +1,315 @@
+use crate::{
+    checksum::{ChecksumMismatch, Digest, HashingSender},
+    quota::{QuotaError, QuotaPermit},
+    scan::{ScanRejected, ScanSession, ScanVerdict, Scanned},
+    sniff::{BodyStart, ContentTypeConfig, ContentTypeMismatch, StartError},
+    status::{FailureReason, TransferStatus},
+    transfer::{Abort, AbortHandle, BodyInfo, SenderInfo, TransferAborted},
+    upload::{UploadTimeout, UploadTimer},
+};
+use actix_web::{error::PayloadError, web::Payload};
+use actix_ws::ProtocolError;
+use bytes::Bytes;
+use futures::StreamExt;
+
+pub enum Part {
+    Chunk(Bytes),
+    /// The payload is complete, the digest is verified along with the one the receiver was told.
+    End {
+        digest: Option<Digest>,
+    },
+}
+
+pub struct Controls<'r> {
+    pub timer: &'r mut UploadTimer,
+    pub info: &'r mut BodyInfo,
+    pub permit: &'r mut QuotaPermit,
+}
+
+pub trait Upload {
+    type Message;
+
+    async fn recv(&mut self) -> Result<Self::Message, RelayError>;
+
+    /// The part of the payload in `msg`, if any, anything else is handled by the upload itself.
+    async fn handle(
+        &mut self,
+        msg: Self::Message,
+        controls: Controls<'_>,
+    ) -> Result<Option<Part>, RelayError>;
+
+    async fn relayed(&mut self, _bytes: u64) -> Result<(), RelayError> {
+        Ok(())
+    }
+}
+
+pub struct Relayed {
+    pub bytes: u64,
+    pub digest: Digest,
+    pub verdict: Option<ScanVerdict>,
+}
+
+#[derive(Debug, thiserror::Error, actix_web_error::Json)]
+pub enum RelayError {
+    #[error("The receiver disconnected")]
+    #[status(400)]
+    ReceiverDisconnected,
+    #[error("The sender disconnected")]
+    #[status(400)]
+    SenderDisconnected,
+    #[error("The transfer was cancelled")]
+    #[status(400)]
+    Cancelled(Option<String>),
+    #[error("The transfer was {}", Abort::Declined(.0.clone()).message())]
+    #[status(400)]
+    Declined(Option<String>),
+    #[error("Transfer timed out: {0}")]
+    #[status(400)]
+    Timeout(UploadTimeout),
+    #[error("The payload couldn't be processed: {0}")]
+    #[status(400)]
+    Payload(PayloadError),
+    #[error("Bad message: {0}")]
+    #[status(400)]
+    BadMessage(String),
+    #[error("Protocol error: {0}")]
+    #[status(400)]
+    Protocol(ProtocolError),
+    #[error(transparent)]
+    #[status(transparent)]
+    Quota(#[from] QuotaError),
+    #[error(transparent)]
+    #[status(400)]
+    ChecksumMismatch(#[from] ChecksumMismatch),
+    #[error(transparent)]
+    #[status(415)]
+    ContentTypeMismatch(#[from] ContentTypeMismatch),
+    #[error(transparent)]
+    #[status(422)]
+    ScanRejected(#[from] ScanRejected),
+}
+
+impl From<StartError> for RelayError {
+    fn from(e: StartError) -> Self {
+        match e {
+            StartError::ReceiverDisconnected => Self::ReceiverDisconnected,
+            StartError::Mismatch(e) => Self::ContentTypeMismatch(e),
+        }
+    }
+}
+
+impl From<Abort> for RelayError {
+    fn from(abort: Abort) -> Self {
+        match abort {
+            Abort::Cancelled => Self::Cancelled(None),
+            Abort::Declined(reason) => Self::Declined(reason),
+        }
+    }
+}
+
+impl From<&RelayError> for FailureReason {
+    fn from(e: &RelayError) -> Self {
+        match e {
+            RelayError::ReceiverDisconnected => Self::ReceiverDisconnected,
+            RelayError::SenderDisconnected => Self::SenderDisconnected,
+            RelayError::Cancelled(_) => Self::SenderCancelled,
+            RelayError::Declined(_) => Self::ReceiverDeclined,
+            RelayError::Timeout(t) => (*t).into(),
+            RelayError::Payload(_) | RelayError::BadMessage(_) | RelayError::Protocol(_) => {
+                Self::PayloadError
+            }
+            RelayError::Quota(_) => Self::QuotaExceeded,
+            RelayError::ChecksumMismatch(_) => Self::ChecksumMismatch,
+            RelayError::ContentTypeMismatch(_) => Self::ContentTypeMismatch,
+            RelayError::ScanRejected(e) => e.into(),
+        }
+    }
+}
+
+impl RelayError {
+    fn abort_message(&self) -> String {
+        match self {
+            RelayError::Cancelled(reason) => {
+                reason.clone().unwrap_or_else(|| Abort::Cancelled.message())
+            }
+            RelayError::Declined(reason) => Abort::Declined(reason.clone()).message(),
+            e => e.to_string(),
+        }
+    }
+}
+
+pub fn status(res: &Result<Relayed, RelayError>) -> TransferStatus {
+    match res {
+        Ok(relayed) => TransferStatus::completed(relayed.digest.clone(), relayed.verdict.clone()),
+        Err(RelayError::Declined(reason)) => TransferStatus::declined(reason.clone()),
+        Err(e) => TransferStatus::failed(e),
+    }
+}
+
+/// With a scanner, the payload is spooled and only sent once the verdict allows it.
+pub struct Relay<'a> {
+    sender: HashingSender<'a>,
+    start: BodyStart<'a>,
+    timer: UploadTimer,
+    permit: &'a mut QuotaPermit,
+    scan: Option<ScanSession>,
+    abort: &'a AbortHandle,
+}
+
+impl<'a> Relay<'a> {
+    pub fn new(
+        info: &'a mut SenderInfo,
+        content_types: &ContentTypeConfig,
+        strip_metadata: bool,
+        scan: Option<ScanSession>,
+    ) -> Self {
+        let SenderInfo {
+            record,
+            sender,
+            body_info_tx,
+            limits,
+            permit,
+            abort,
+        } = info;
+        let digest = record.digest.clone();
+        let mut start = BodyStart::new(body_info_tx, record, content_types, strip_metadata);
+        // until the upload announces another one
+        start.info.digest = digest;
+        Self {
+            sender: HashingSender::new(sender),
+            start,
+            timer: UploadTimer::new(*limits),
+            permit,
+            scan,
+            abort,
+        }
+    }
+
+    pub fn info(&mut self) -> &mut BodyInfo {
+        &mut self.start.info
+    }
+
+    pub async fn run(mut self, upload: &mut impl Upload) -> Result<Relayed, RelayError> {
+        let res = self.relay(upload).await;
+        // a receiver that declined usually disconnects right away, its reason wins
+        let res = match (res, self.abort.reason()) {
+            (Err(_), Some(abort)) => Err(abort.clone().into()),
+            (res, _) => res,
+        };
+        if let Err(e) = &res {
+            self.sender
+                .abort(TransferAborted(e.abort_message()))
+                .await
+                .ok();
+        }
+        res
+    }
+
+    async fn relay(&mut self, upload: &mut impl Upload) -> Result<Relayed, RelayError> {
+        let mut bytes = 0u64;
+
+        loop {
+            let msg = tokio::select! {
+                msg = self.timer.timeout(upload.recv()) => msg,
+                _ = self.sender.closed() => return Err(RelayError::ReceiverDisconnected),
+                abort = self.abort.aborted() => return Err(abort.into()),
+            };
+            let msg = msg.map_err(RelayError::Timeout)??;
+            let controls = Controls {
+                timer: &mut self.timer,
+                info: &mut self.start.info,
+                permit: self.permit,
+            };
+            match upload.handle(msg, controls).await? {
+                Some(Part::Chunk(buf)) => {
+                    if let Some(stripper) = self.start.start(&buf)? {
+                        self.sender.strip_metadata(stripper);
+                    }
+                    self.timer.resume();
+                    self.timer.record(buf.len()).map_err(RelayError::Timeout)?;
+                    self.permit.consume(buf.len())?;
+                    bytes += buf.len() as u64;
+                    match &mut self.scan {
+                        // the receiver gets the spooled payload once it's scanned
+                        Some(scan) => {
+                            self.sender.hash(&buf);
+                            scan.write(&buf).await;
+                        }
+                        None => {
+                            if self.sender.send(buf).await.is_err() {
+                                return Err(RelayError::ReceiverDisconnected);
+                            }
+                        }
+                    }
+                    upload.relayed(bytes).await?;
+                }
+                Some(Part::End { digest }) => {
+                    self.start.start(&[])?;
+                    // the receiver was told about the announced digest, so it's always verified
+                    let computed = self.sender.verify(self.start.info.digest.as_ref())?;
+                    if let Some(digest) = digest {
+                        computed.verify(&digest)?;
+                    }
+                    let verdict = match self.scan.take() {
+                        Some(scan) => tokio::select! {
+                            verdict = deliver(scan, &mut self.sender) => Some(verdict?),
+                            abort = self.abort.aborted() => return Err(abort.into()),
+                        },
+                        None => None,
+                    };
+                    return match self.sender.flush().await {
+                        Ok(_) => Ok(Relayed {
+                            bytes,
+                            digest: computed,
+                            verdict,
+                        }),
+                        Err(_) => Err(RelayError::ReceiverDisconnected),
+                    };
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+/// Waits for the verdict and sends the spooled payload, except for the last chunk.
+async fn deliver(
+    scan: ScanSession,
+    sender: &mut HashingSender<'_>,
+) -> Result<ScanVerdict, RelayError> {
+    let Scanned { verdict, mut spool } = scan.finish().await?;
+    loop {
+        let chunk = spool
+            .next_chunk()
+            .await
+            .map_err(|e| ScanRejected::Failed(format!("Couldn't read the spooled payload: {e}")))?;
+        match chunk {
+            Some(chunk) => sender
+                .forward(chunk)
+                .await
+                .map_err(|_| RelayError::ReceiverDisconnected)?,
+            None => return Ok(verdict),
+        }
+    }
+}
+
+pub struct BodyUpload(pub Payload);
+
+impl Upload for BodyUpload {
+    type Message = Option<Bytes>;
+
+    async fn recv(&mut self) -> Result<Option<Bytes>, RelayError> {
+        self.0.next().await.transpose().map_err(RelayError::Payload)
+    }
+
+    async fn handle(
+        &mut self,
+        msg: Option<Bytes>,
+        _: Controls<'_>,
+    ) -> Result<Option<Part>, RelayError> {
+        Ok(Some(match msg {
+            Some(buf) => Part::Chunk(buf),
+            None => Part::End { digest: None },
+        }))
+    }
+}
@@ -0,0 +1,610 @@
+use crate::{
+    cleanup::{Cleanup, RemoveSpool},
+    status::FailureReason,
+};
+use actix::Addr;
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    process::Command,
+};
+use tracing::warn;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// clamd's default `StreamMaxLength` is 25 MiB, larger chunks are split up anyway.
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+const SPOOL_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct ScanConfig {
+    #[serde(default)]
+    pub clamd: Option<String>,
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    #[serde(default)]
+    pub action: InfectedAction,
+    #[serde(default)]
+    pub quarantine_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub spool_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub fail_open: bool,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            clamd: None,
+            command: None,
+            action: InfectedAction::default(),
+            quarantine_dir: None,
+            spool_dir: None,
+            fail_open: false,
+            timeout: default_timeout(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InfectedAction {
+    #[default]
+    Reject,
+    Quarantine,
+    Annotate,
+}
+
+/// The result of scanning a payload, shown to the sender and the receiver.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "result", rename_all = "camelCase")]
+pub enum ScanVerdict {
+    Clean,
+    Infected { finding: String },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ScanRejected {
+    #[error("The payload is infected: {0}")]
+    Infected(String),
+    #[error("The payload couldn't be scanned: {0}")]
+    Failed(String),
+}
+
+impl From<&ScanRejected> for FailureReason {
+    fn from(e: &ScanRejected) -> Self {
+        match e {
+            ScanRejected::Infected(_) => Self::Infected,
+            ScanRejected::Failed(_) => Self::ScanFailed,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Scanner {
+    config: ScanConfig,
+    cleanup: Addr<Cleanup>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// The payload is spooled, the receiver only gets it from the spool once the verdict allows it.
+pub struct ScanSession {
+    config: ScanConfig,
+    filename: String,
+    clamd: Option<Box<dyn Stream>>,
+    spool: Option<Spool>,
+    error: Option<String>,
+    /// The payload can't be delivered without its spool, even with `fail_open`.
+    spool_error: Option<String>,
+}
+
+/// A spooled payload, the cleanup removes the file once this is dropped.
+pub struct Spool {
+    path: PathBuf,
+    file: Option<File>,
+    cleanup: Addr<Cleanup>,
+}
+
+pub struct Scanned {
+    pub verdict: ScanVerdict,
+    pub spool: Spool,
+}
+
+impl Scanner {
+    pub fn new(config: &ScanConfig, cleanup: &Addr<Cleanup>) -> Option<Self> {
+        (config.clamd.is_some() || config.command.is_some()).then(|| Self {
+            config: config.clone(),
+            cleanup: cleanup.clone(),
+        })
+    }
+
+    pub async fn start(&self, id: Uuid, filename: &str) -> ScanSession {
+        let mut session = ScanSession {
+            config: self.config.clone(),
+            filename: filename.to_owned(),
+            clamd: None,
+            spool: None,
+            error: None,
+            spool_error: None,
+        };
+        if let Some(address) = &self.config.clamd {
+            match connect_clamd(address).await {
+                Ok(stream) => session.clamd = Some(stream),
+                Err(e) => session.fail(format!("Couldn't connect to clamd: {e}")),
+            }
+        }
+        let dir = self
+            .config
+            .spool_dir
+            .clone()
+            .unwrap_or_else(std::env::temp_dir);
+        match Spool::create(dir.join(format!("frachter-{id}")), self.cleanup.clone()).await {
+            Ok(spool) => session.spool = Some(spool),
+            Err(e) => session.spool_error = Some(format!("Couldn't spool the payload: {e}")),
+        }
+        session
+    }
+}
+
+impl ScanSession {
+    pub async fn write(&mut self, chunk: &[u8]) {
+        if let (None, Some(spool)) = (&self.spool_error, &mut self.spool) {
+            if let Err(e) = spool.file().write_all(chunk).await {
+                self.spool_error = Some(format!("Couldn't spool the payload: {e}"));
+            }
+        }
+        if self.error.is_some() {
+            return;
+        }
+        if let Some(clamd) = &mut self.clamd {
+            for part in chunk.chunks(CLAMD_CHUNK_SIZE) {
+                let res = async {
+                    clamd.write_u32(part.len() as u32).await?;
+                    clamd.write_all(part).await
+                }
+                .await;
+                if let Err(e) = res {
+                    return self.fail(format!("Couldn't stream to clamd: {e}"));
+                }
+            }
+        }
+    }
+
+    /// It's an error if the payload must not be delivered.
+    pub async fn finish(mut self) -> Result<Scanned, ScanRejected> {
+        let mut spool = match (self.spool_error.take(), self.spool.take()) {
+            (None, Some(spool)) => spool,
+            (error, _) => return Err(ScanRejected::Failed(error.unwrap_or_default())),
+        };
+        if let Err(e) = spool.file().flush().await {
+            return Err(ScanRejected::Failed(format!(
+                "Couldn't spool the payload: {e}"
+            )));
+        }
+        let timeout = Duration::from_secs(self.config.timeout);
+        let verdict = match self.error.take() {
+            Some(message) => ScanVerdict::Error { message },
+            None => match tokio::time::timeout(timeout, self.verdict(&spool.path)).await {
+                Ok(Ok(verdict)) => verdict,
+                Ok(Err(e)) => ScanVerdict::Error {
+                    message: e.to_string(),
+                },
+                Err(_) => ScanVerdict::Error {
+                    message: "The scanner timed out".to_owned(),
+                },
+            },
+        };
+
+        if let (ScanVerdict::Infected { .. }, InfectedAction::Quarantine, Some(dir)) =
+            (&verdict, self.config.action, &self.config.quarantine_dir)
+        {
+            let target = dir.join(format!(
+                "{}-{}",
+                spool.path.file_name().unwrap_or_default().to_string_lossy(),
+                self.filename
+            ));
+            spool.file.take();
+            if let Err(e) = move_file(&spool.path, &target).await {
+                warn!(path = %spool.path.display(), error = %e, "Couldn't quarantine a payload");
+            }
+        }
+
+        match verdict {
+            ScanVerdict::Infected { finding } if self.config.action != InfectedAction::Annotate => {
+                Err(ScanRejected::Infected(finding))
+            }
+            ScanVerdict::Error { message } if !self.config.fail_open => {
+                Err(ScanRejected::Failed(message))
+            }
+            verdict => match spool.file().seek(SeekFrom::Start(0)).await {
+                Ok(_) => Ok(Scanned { verdict, spool }),
+                Err(e) => Err(ScanRejected::Failed(format!(
+                    "Couldn't read the spooled payload: {e}"
+                ))),
+            },
+        }
+    }
+
+    async fn verdict(&mut self, spool: &Path) -> io::Result<ScanVerdict> {
+        if let Some(mut clamd) = self.clamd.take() {
+            clamd.write_u32(0).await?;
+            let mut reply = String::new();
+            clamd.read_to_string(&mut reply).await?;
+            let verdict = parse_clamd_reply(&reply);
+            if verdict != ScanVerdict::Clean {
+                return Ok(verdict);
+            }
+        }
+        match &self.config.command {
+            Some(command) => run_command(command, spool).await,
+            None => Ok(ScanVerdict::Clean),
+        }
+    }
+
+    fn fail(&mut self, message: String) {
+        self.error.get_or_insert(message);
+    }
+}
+
+impl Spool {
+    async fn create(path: PathBuf, cleanup: Addr<Cleanup>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            // the spool dir may be shared, an existing file or symlink is never reused
+            .create_new(true)
+            .open(&path)
+            .await?;
+        Ok(Self {
+            path,
+            file: Some(file),
+            cleanup,
+        })
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.file
+            .as_mut()
+            .expect("the spool is open until it's dropped")
+    }
+
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let mut buf = BytesMut::with_capacity(SPOOL_CHUNK_SIZE);
+        match self.file().read_buf(&mut buf).await? {
+            0 => Ok(None),
+            _ => Ok(Some(buf.freeze())),
+        }
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        // the file has to be closed before it can be removed everywhere
+        self.file.take();
+        self.cleanup
+            .do_send(RemoveSpool(std::mem::take(&mut self.path)));
+    }
+}
+
+async fn connect_clamd(address: &str) -> io::Result<Box<dyn Stream>> {
+    let mut stream: Box<dyn Stream> = match address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets aren't supported on this platform",
+            ))
+        }
+        None => Box::new(TcpStream::connect(address).await?),
+    };
+    stream.write_all(b"zINSTREAM\0").await?;
+    Ok(stream)
+}
+
+/// Parses replies like `stream: OK` or `stream: Eicar-Signature FOUND`.
+fn parse_clamd_reply(reply: &str) -> ScanVerdict {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        ScanVerdict::Clean
+    } else if let Some(finding) = result.strip_suffix(" FOUND") {
+        ScanVerdict::Infected {
+            finding: finding.to_owned(),
+        }
+    } else {
+        ScanVerdict::Error {
+            message: format!("clamd: {result}"),
+        }
+    }
+}
+
+async fn run_command(command: &[String], path: &Path) -> io::Result<ScanVerdict> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the scan command is empty"))?;
+    let output = Command::new(program)
+        .args(args)
+        .arg(path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let last_line = stdout.lines().rev().find(|l| !l.trim().is_empty());
+
+    Ok(match output.status.code() {
+        Some(0) => ScanVerdict::Clean,
+        Some(1) => ScanVerdict::Infected {
+            finding: last_line.unwrap_or("unknown").trim().to_owned(),
+        },
+        _ => ScanVerdict::Error {
+            message: format!(
+                "The scan command failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        },
+    })
+}
+
+/// Renames `from`, or copies it if `to` is on another filesystem.
+async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_err() {
+        tokio::fs::copy(from, to).await?;
+        tokio::fs::remove_file(from).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{devices::Devices, store::Store, Transfers};
+    use actix::Actor;
+    use tokio::net::TcpListener;
+
+    /// Accepts one INSTREAM session and replies with `FOUND` if the payload contains `EICAR`.
+    async fn fake_clamd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut payload = Vec::new();
+            loop {
+                let len = stream.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let start = payload.len();
+                payload.resize(start + len, 0);
+                stream.read_exact(&mut payload[start..]).await.unwrap();
+            }
+            let reply = match payload.windows(5).any(|w| w == b"EICAR") {
+                true => "stream: Eicar-Signature FOUND\0",
+                false => "stream: OK\0",
+            };
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        });
+        address
+    }
+
+    fn config(clamd: Option<String>, command: Option<&str>) -> ScanConfig {
+        ScanConfig {
+            clamd,
+            command: command.map(|c| {
+                vec![
+                    "sh".to_owned(),
+                    "-c".to_owned(),
+                    c.to_owned(),
+                    "sh".to_owned(),
+                ]
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn cleanup() -> Addr<Cleanup> {
+        Cleanup::new(
+            Transfers::new(),
+            Duration::from_secs(60),
+            Store::disabled(),
+            Devices::new(Store::disabled()),
+        )
+        .start()
+    }
+
+    async fn scan(config: &ScanConfig, payload: &[u8]) -> Result<ScanVerdict, ScanRejected> {
+        let mut session = Scanner::new(config, &cleanup())
+            .unwrap()
+            .start(Uuid::new_v4(), "file.txt")
+            .await;
+        for chunk in payload.chunks(3) {
+            session.write(chunk).await;
+        }
+        session.finish().await.map(|scanned| scanned.verdict)
+    }
+
+    fn spool_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("frachter-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn is_empty(dir: &Path) -> bool {
+        std::fs::read_dir(dir).unwrap().next().is_none()
+    }
+
+    async fn emptied(dir: &Path) -> bool {
+        for _ in 0..100 {
+            if is_empty(dir) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[test]
+    fn parses_clamd_replies() {
+        assert_eq!(parse_clamd_reply("stream: OK\0"), ScanVerdict::Clean);
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Signature FOUND\0"),
+            ScanVerdict::Infected {
+                finding: "Eicar-Signature".to_owned()
+            }
+        );
+        assert!(matches!(
+            parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0"),
+            ScanVerdict::Error { .. }
+        ));
+    }
+
+    #[actix_web::test]
+    async fn streams_to_clamd() {
+        let clean = config(Some(fake_clamd().await), None);
+        assert_eq!(scan(&clean, b"harmless").await, Ok(ScanVerdict::Clean));
+        let infected = config(Some(fake_clamd().await), None);
+        assert_eq!(
+            scan(&infected, b"an EICAR test").await,
+            Err(ScanRejected::Infected("Eicar-Signature".to_owned()))
+        );
+    }
+
+    #[actix_web::test]
+    async fn runs_commands_on_spooled_payloads() {
+        let command = r#"if grep -q EICAR "$1"; then echo "Found: EICAR"; exit 1; fi"#;
+        let mut annotate = config(None, Some(command));
+        annotate.action = InfectedAction::Annotate;
+        assert_eq!(
+            scan(&annotate, b"an EICAR test").await,
+            Ok(ScanVerdict::Infected {
+                finding: "Found: EICAR".to_owned()
+            })
+        );
+        assert_eq!(scan(&annotate, b"harmless").await, Ok(ScanVerdict::Clean));
+
+        let broken = config(None, Some("exit 2"));
+        assert!(matches!(
+            scan(&broken, b"harmless").await,
+            Err(ScanRejected::Failed(_))
+        ));
+        let fail_open = ScanConfig {
+            fail_open: true,
+            ..broken
+        };
+        assert!(matches!(
+            scan(&fail_open, b"harmless").await,
+            Ok(ScanVerdict::Error { .. })
+        ));
+    }
+
+    #[actix_web::test]
+    async fn delivers_the_spooled_payload_and_removes_it() {
+        let dir = spool_dir();
+        let config = ScanConfig {
+            spool_dir: Some(dir.clone()),
+            ..config(Some(fake_clamd().await), None)
+        };
+        let mut session = Scanner::new(&config, &cleanup())
+            .unwrap()
+            .start(Uuid::new_v4(), "file.txt")
+            .await;
+        session.write(b"harm").await;
+        session.write(b"less").await;
+
+        let Scanned { verdict, mut spool } = session.finish().await.unwrap();
+        assert_eq!(verdict, ScanVerdict::Clean);
+        let mut delivered = Vec::new();
+        while let Some(chunk) = spool.next_chunk().await.unwrap() {
+            delivered.extend_from_slice(&chunk);
+        }
+        assert_eq!(delivered, b"harmless");
+        assert!(!is_empty(&dir));
+        drop(spool);
+        assert!(emptied(&dir).await);
+        std::fs::remove_dir(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn removes_the_spool_of_abandoned_sessions() {
+        let dir = spool_dir();
+        let config = ScanConfig {
+            spool_dir: Some(dir.clone()),
+            ..config(None, Some("exit 0"))
+        };
+        let mut session = Scanner::new(&config, &cleanup())
+            .unwrap()
+            .start(Uuid::new_v4(), "file.txt")
+            .await;
+        session.write(b"the upload failed").await;
+        assert!(!is_empty(&dir));
+
+        drop(session);
+        assert!(emptied(&dir).await);
+        std::fs::remove_dir(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn never_delivers_payloads_that_couldnt_be_spooled() {
+        let config = ScanConfig {
+            spool_dir: Some(std::env::temp_dir().join(format!("missing-{}", Uuid::new_v4()))),
+            fail_open: true,
+            ..config(Some(fake_clamd().await), None)
+        };
+        assert!(matches!(
+            scan(&config, b"harmless").await,
+            Err(ScanRejected::Failed(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn never_spools_to_existing_files() {
+        let dir = spool_dir();
+        let target = dir.join("target");
+        std::fs::write(&target, b"keep me").unwrap();
+        let id = Uuid::new_v4();
+        std::os::unix::fs::symlink(&target, dir.join(format!("frachter-{id}"))).unwrap();
+        let config = ScanConfig {
+            spool_dir: Some(dir.clone()),
+            fail_open: true,
+            ..config(Some(fake_clamd().await), None)
+        };
+
+        let mut session = Scanner::new(&config, &cleanup())
+            .unwrap()
+            .start(id, "file.txt")
+            .await;
+        session.write(b"overwritten").await;
+        assert!(matches!(
+            session.finish().await,
+            Err(ScanRejected::Failed(_))
+        ));
+        assert_eq!(std::fs::read(&target).unwrap(), b"keep me");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
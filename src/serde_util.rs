@@ -1,9 +1,13 @@
 pub mod mime {
-    use std::fmt::Formatter;
-    use serde::{Deserializer, Serializer};
     use serde::de::{Error, Visitor};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Formatter;
 
-    pub fn deserialize<'de, D>(de: D) -> Result<mime::Mime, D::Error> where D: Deserializer<'de> {
+    #[allow(unused)]
+    pub fn deserialize<'de, D>(de: D) -> Result<mime::Mime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
         struct Vis;
         impl Visitor<'_> for Vis {
             type Value = mime::Mime;
@@ -12,15 +16,57 @@ pub mod mime {
                 write!(f, "a content type (mime)")
             }
 
-            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: Error {
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
                 v.parse().map_err(|e| E::custom(e))
             }
         }
         de.deserialize_str(Vis)
     }
 
+    /// Like [`deserialize`], but an empty or missing type is `None`.
+    pub fn deserialize_optional<'de, D>(de: D) -> Result<Option<mime::Mime>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(de)?.filter(|s| !s.is_empty()) {
+            Some(s) => s.parse().map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+
     #[allow(unused)]
-    pub fn serialize<S>(mime: &mime::Mime, ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    pub fn serialize<S>(mime: &mime::Mime, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
         ser.serialize_str(mime.as_ref())
     }
-}
\ No newline at end of file
+}
+
+/// (De)serializes optional bytes as standard base64.
+pub mod base64 {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn deserialize<'de, D>(de: D) -> Result<Option<Vec<u8>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(de)?
+            .map(|s| STANDARD.decode(s).map_err(D::Error::custom))
+            .transpose()
+    }
+
+    pub fn serialize<S>(bytes: &Option<Vec<u8>>, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match bytes {
+            Some(bytes) => ser.serialize_some(&STANDARD.encode(bytes)),
+            None => ser.serialize_none(),
+        }
+    }
+}
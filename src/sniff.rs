@@ -0,0 +1,184 @@
+use crate::{
+    history::TransferRecord, images::MetadataStripper, status::FailureReason, transfer::BodyInfo,
+};
+use actix_web::http::header::ContentEncoding;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ContentTypeConfig {
+    #[serde(default)]
+    pub mismatch: MismatchPolicy,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MismatchPolicy {
+    #[default]
+    Declared,
+    Detected,
+    Reject,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("The payload looks like {detected}, not {declared}")]
+pub struct ContentTypeMismatch {
+    pub declared: String,
+    pub detected: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("The receiver disconnected")]
+    ReceiverDisconnected,
+    #[error(transparent)]
+    Mismatch(#[from] ContentTypeMismatch),
+}
+
+impl From<&StartError> for FailureReason {
+    fn from(e: &StartError) -> Self {
+        match e {
+            StartError::ReceiverDisconnected => Self::ReceiverDisconnected,
+            StartError::Mismatch(_) => Self::ContentTypeMismatch,
+        }
+    }
+}
+
+pub fn from_magic(buf: &[u8]) -> Option<mime::Mime> {
+    infer::get(buf).and_then(|t| t.mime_type().parse().ok())
+}
+
+pub fn from_extension(filename: &str) -> Option<mime::Mime> {
+    mime_guess::from_path(filename).first()
+}
+
+/// The type a transfer is created with, before any bytes are known.
+pub fn provisional(declared: Option<&mime::Mime>, filename: &str) -> mime::Mime {
+    declared
+        .cloned()
+        .or_else(|| from_extension(filename))
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM)
+}
+
+impl MismatchPolicy {
+    /// The type to serve instead of the provisional one, if any.
+    pub fn resolve(
+        self,
+        declared: Option<&mime::Mime>,
+        detected: Option<&mime::Mime>,
+    ) -> Result<Option<mime::Mime>, ContentTypeMismatch> {
+        let (declared, detected) = match (declared, detected) {
+            (_, None) => return Ok(None),
+            (None, Some(detected)) => return Ok(Some(detected.clone())),
+            (Some(declared), Some(detected)) => (declared, detected),
+        };
+        if declared.essence_str() == detected.essence_str() {
+            return Ok(None);
+        }
+        match self {
+            MismatchPolicy::Declared => Ok(None),
+            MismatchPolicy::Detected => Ok(Some(detected.clone())),
+            MismatchPolicy::Reject => Err(ContentTypeMismatch {
+                declared: declared.essence_str().to_owned(),
+                detected: detected.essence_str().to_owned(),
+            }),
+        }
+    }
+}
+
+pub struct BodyStart<'a> {
+    tx: &'a mut Option<oneshot::Sender<BodyInfo>>,
+    record: &'a mut TransferRecord,
+    policy: MismatchPolicy,
+    strip_metadata: bool,
+    pub info: BodyInfo,
+}
+
+impl<'a> BodyStart<'a> {
+    pub fn new(
+        tx: &'a mut Option<oneshot::Sender<BodyInfo>>,
+        record: &'a mut TransferRecord,
+        config: &ContentTypeConfig,
+        strip_metadata: bool,
+    ) -> Self {
+        Self {
+            tx,
+            record,
+            policy: config.mismatch,
+            strip_metadata,
+            info: BodyInfo::default(),
+        }
+    }
+
+    /// `first` is the start of the payload, it's empty if the payload is.
+    /// Returns a stripper if the payload is a JPEG whose metadata has to be removed.
+    pub fn start(&mut self, first: &[u8]) -> Result<Option<MetadataStripper>, StartError> {
+        let Some(tx) = self.tx.take() else {
+            return Ok(None);
+        };
+        let mut info = self.info.clone();
+        let mut stripper = None;
+        // the magic numbers of encoded payloads are those of their encoding
+        if info.content_encoding == ContentEncoding::Identity {
+            self.record.detected_type = from_magic(first);
+            if self.strip_metadata && MetadataStripper::is_jpeg(first) {
+                stripper = Some(MetadataStripper::default());
+                info.content_length = None;
+                info.modified = true;
+            }
+        }
+        info.content_type = self.policy.resolve(
+            self.record.declared_type.as_ref(),
+            self.record.detected_type.as_ref(),
+        )?;
+        tx.send(info)
+            .map_err(|_| StartError::ReceiverDisconnected)?;
+
+        Ok(stripper)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn detects_magic_numbers_and_extensions() {
+        assert_eq!(from_magic(PNG), Some(mime::IMAGE_PNG));
+        assert_eq!(from_magic(b"<!DOCTYPE html><html>"), Some(mime::TEXT_HTML));
+        assert_eq!(from_magic(b"just some text"), None);
+        assert_eq!(from_extension("photo.JPG"), Some(mime::IMAGE_JPEG));
+        assert_eq!(from_extension("no-extension"), None);
+        assert_eq!(
+            provisional(None, "no-extension"),
+            mime::APPLICATION_OCTET_STREAM
+        );
+        assert_eq!(
+            provisional(Some(&mime::TEXT_PLAIN), "photo.jpg"),
+            mime::TEXT_PLAIN
+        );
+    }
+
+    #[test]
+    fn resolves_mismatches_by_policy() {
+        let (png, html) = (Some(&mime::IMAGE_PNG), Some(&mime::TEXT_HTML));
+        for policy in [
+            MismatchPolicy::Declared,
+            MismatchPolicy::Detected,
+            MismatchPolicy::Reject,
+        ] {
+            assert_eq!(policy.resolve(png, None).unwrap(), None);
+            assert_eq!(policy.resolve(png, png).unwrap(), None);
+            assert_eq!(policy.resolve(None, html).unwrap(), Some(mime::TEXT_HTML));
+        }
+        assert_eq!(MismatchPolicy::Declared.resolve(png, html).unwrap(), None);
+        assert_eq!(
+            MismatchPolicy::Detected.resolve(png, html).unwrap(),
+            Some(mime::TEXT_HTML)
+        );
+        assert!(MismatchPolicy::Reject.resolve(png, html).is_err());
+    }
+}
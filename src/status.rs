@@ -0,0 +1,80 @@
+use crate::{checksum::Digest, scan::ScanVerdict, upload::UploadTimeout};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// The outcome of a transfer, kept for a while after it finished.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum TransferStatus {
+    Completed {
+        /// The digest of the relayed payload.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        digest: Option<Digest>,
+        /// The content scanner's verdict, if one is configured.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scan: Option<ScanVerdict>,
+    },
+    Failed {
+        reason: FailureReason,
+        /// Given by whoever ended the transfer, e.g. the receiver declining it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FailureReason {
+    ReceiverDisconnected,
+    SenderDisconnected,
+    SenderCancelled,
+    /// Unlike `receiverDisconnected`, the receiver ended the transfer on purpose.
+    ReceiverDeclined,
+    PayloadError,
+    IdleTimeout,
+    MaxDurationExceeded,
+    TooSlow,
+    QuotaExceeded,
+    Expired,
+    ChecksumMismatch,
+    ContentTypeMismatch,
+    Infected,
+    ScanFailed,
+}
+
+impl TransferStatus {
+    pub fn completed(digest: Digest, scan: Option<ScanVerdict>) -> Self {
+        Self::Completed {
+            digest: Some(digest),
+            scan,
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        matches!(self, Self::Completed { .. })
+    }
+
+    pub fn failed(reason: impl Into<FailureReason>) -> Self {
+        Self::Failed {
+            reason: reason.into(),
+            message: None,
+        }
+    }
+
+    pub fn declined(reason: Option<String>) -> Self {
+        Self::Failed {
+            reason: FailureReason::ReceiverDeclined,
+            message: reason,
+        }
+    }
+}
+
+impl From<UploadTimeout> for FailureReason {
+    fn from(timeout: UploadTimeout) -> Self {
+        match timeout {
+            UploadTimeout::Idle => Self::IdleTimeout,
+            UploadTimeout::MaxDuration => Self::MaxDurationExceeded,
+            UploadTimeout::TooSlow => Self::TooSlow,
+        }
+    }
+}
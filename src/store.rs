@@ -0,0 +1,542 @@
+use crate::{
+    checksum::Digest,
+    history::{HistoryEntry, HistoryPage, HistoryQuery},
+    status::TransferStatus,
+    upload::UploadLimits,
+};
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    ops::Bound,
+    path::Path,
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+const TRANSFERS: TableDefinition<u128, &[u8]> = TableDefinition::new("transfers");
+const STATUSES: TableDefinition<u128, &[u8]> = TableDefinition::new("statuses");
+/// Keyed by user, completion time and id, so a user's entries are sorted by time.
+const HISTORY: TableDefinition<(&str, i64, u128), &[u8]> = TableDefinition::new("history");
+const DEVICES: TableDefinition<u128, &[u8]> = TableDefinition::new("devices");
+
+type Table = TableDefinition<'static, u128, &'static [u8]>;
+
+/// Writes are committed on a thread of their own, so callers don't wait for the disk. Reads see
+/// everything written before them, they wait for the queued writes and should run on a blocking
+/// thread. A disabled store silently drops everything.
+#[derive(Clone)]
+pub struct Store(Option<Arc<Inner>>);
+
+struct Inner {
+    db: Arc<Database>,
+    writes: Option<mpsc::Sender<Write>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+enum Write {
+    Put(Table, Uuid, Vec<u8>),
+    Remove(Table, Uuid),
+    History {
+        user: String,
+        completed_at: i64,
+        id: Uuid,
+        value: Vec<u8>,
+    },
+    /// Answered once the writes before it are committed.
+    Flush(mpsc::SyncSender<()>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredTransfer {
+    pub filename: String,
+    pub content_type: String,
+    pub user: String,
+    pub limits: UploadLimits,
+    #[serde(default)]
+    pub digest: Option<Digest>,
+    #[serde(default)]
+    pub preview: bool,
+    /// The content type the sender declared, `content_type` can be derived from the filename.
+    #[serde(default)]
+    pub declared_type: Option<String>,
+    #[serde(default)]
+    pub device: Option<Uuid>,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredStatus {
+    pub status: TransferStatus,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredDevice {
+    pub user: String,
+    pub name: String,
+    pub paired_at: i64,
+    /// The SHA-256 of the device's token, base64url.
+    pub token_hash: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("Database error: {0}")]
+    Database(Box<redb::Error>),
+    #[error("Couldn't (de)serialize an entry: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+macro_rules! from_redb_error {
+    ($($ty:ty),*) => {
+        $(impl From<$ty> for StoreError {
+            fn from(e: $ty) -> Self {
+                Self::Database(Box::new(e.into()))
+            }
+        })*
+    };
+}
+from_redb_error!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+impl Store {
+    pub fn disabled() -> Self {
+        Self(None)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::with_database(Database::create(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        let db = redb::Builder::new()
+            .create_with_backend(redb::backends::InMemoryBackend::new())
+            .unwrap();
+        Self::with_database(db).unwrap()
+    }
+
+    fn with_database(db: Database) -> Result<Self, StoreError> {
+        // create the tables, so reading never fails because they're missing
+        let tx = db.begin_write()?;
+        tx.open_table(TRANSFERS)?;
+        tx.open_table(STATUSES)?;
+        tx.open_table(HISTORY)?;
+        tx.open_table(DEVICES)?;
+        tx.commit()?;
+
+        let db = Arc::new(db);
+        let (writes, rx) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("frachter-store".to_owned())
+            .spawn({
+                let db = db.clone();
+                move || write(&db, rx)
+            })
+            .map_err(|e| StoreError::Database(Box::new(e.into())))?;
+
+        Ok(Self(Some(Arc::new(Inner {
+            db,
+            writes: Some(writes),
+            writer: Some(writer),
+        }))))
+    }
+
+    pub fn put_transfer(&self, id: Uuid, transfer: &StoredTransfer) {
+        self.put(TRANSFERS, id, transfer);
+    }
+
+    pub fn remove_transfer(&self, id: Uuid) {
+        self.remove(TRANSFERS, id);
+    }
+
+    pub fn transfers(&self) -> Vec<(Uuid, StoredTransfer)> {
+        self.all(TRANSFERS)
+    }
+
+    pub fn put_status(&self, id: Uuid, status: &StoredStatus) {
+        self.put(STATUSES, id, status);
+    }
+
+    pub fn remove_status(&self, id: Uuid) {
+        self.remove(STATUSES, id);
+    }
+
+    pub fn statuses(&self) -> Vec<(Uuid, StoredStatus)> {
+        self.all(STATUSES)
+    }
+
+    pub fn put_device(&self, id: Uuid, device: &StoredDevice) {
+        self.put(DEVICES, id, device);
+    }
+
+    pub fn remove_device(&self, id: Uuid) {
+        self.remove(DEVICES, id);
+    }
+
+    pub fn devices(&self) -> Vec<(Uuid, StoredDevice)> {
+        self.all(DEVICES)
+    }
+
+    pub fn put_history(&self, user: &str, entry: &HistoryEntry) {
+        let Some(inner) = &self.0 else { return };
+        match serde_json::to_vec(entry) {
+            Ok(value) => inner.write(Write::History {
+                user: user.to_owned(),
+                completed_at: entry.completed_at,
+                id: entry.id,
+                value,
+            }),
+            Err(e) => warn!(id = %entry.id, error = %e, "Couldn't store history entry"),
+        }
+    }
+
+    /// The history entries of `user` matching `query`, newest first.
+    pub fn history(&self, user: &str, query: &HistoryQuery) -> Result<HistoryPage, StoreError> {
+        let mut page = HistoryPage {
+            entries: Vec::new(),
+            more: false,
+        };
+        let Some(inner) = &self.0 else {
+            return Ok(page);
+        };
+        let since = query.since.unwrap_or(i64::MIN);
+        let end = match query.until {
+            Some(until) if until <= since => return Ok(page),
+            Some(until) => Bound::Excluded((user, until, u128::MIN)),
+            None => Bound::Included((user, i64::MAX, u128::MAX)),
+        };
+        inner.flush();
+
+        let tx = inner.db.begin_read()?;
+        let table = tx.open_table(HISTORY)?;
+        let mut skipped = 0;
+        for entry in table
+            .range::<(&str, i64, u128)>((Bound::Included((user, since, u128::MIN)), end))?
+            .rev()
+        {
+            let (_, value) = entry?;
+            let entry: HistoryEntry = match serde_json::from_slice(value.value()) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(error = %e, "Skipping bad history entry");
+                    continue;
+                }
+            };
+            if !query.matches(&entry) {
+                continue;
+            }
+            if skipped < query.offset {
+                skipped += 1;
+            } else if page.entries.len() < query.limit() {
+                page.entries.push(entry);
+            } else {
+                page.more = true;
+                break;
+            }
+        }
+
+        Ok(page)
+    }
+
+    fn put<T: Serialize>(&self, table: Table, id: Uuid, value: &T) {
+        let Some(inner) = &self.0 else { return };
+        match serde_json::to_vec(value) {
+            Ok(value) => inner.write(Write::Put(table, id, value)),
+            Err(e) => warn!(%id, table = %table, error = %e, "Couldn't store entry"),
+        }
+    }
+
+    fn remove(&self, table: Table, id: Uuid) {
+        if let Some(inner) = &self.0 {
+            inner.write(Write::Remove(table, id));
+        }
+    }
+
+    /// Skips entries that can't be read.
+    fn all<T: DeserializeOwned>(&self, table: Table) -> Vec<(Uuid, T)> {
+        let Some(inner) = &self.0 else {
+            return Vec::new();
+        };
+        inner.flush();
+        let res = (|| -> Result<Vec<(Uuid, T)>, StoreError> {
+            let tx = inner.db.begin_read()?;
+            let mut entries = Vec::new();
+            for entry in tx.open_table(table)?.iter()? {
+                let (id, value) = entry?;
+                let id = Uuid::from_u128(id.value());
+                match serde_json::from_slice(value.value()) {
+                    Ok(value) => entries.push((id, value)),
+                    Err(e) => warn!(%id, table = %table, error = %e, "Skipping bad entry"),
+                }
+            }
+            Ok(entries)
+        })();
+        res.unwrap_or_else(|e| {
+            warn!(table = %table, error = %e, "Couldn't read entries");
+            Vec::new()
+        })
+    }
+}
+
+impl Inner {
+    fn write(&self, write: Write) {
+        if let Some(writes) = &self.writes {
+            // the writer only stops once the store is dropped
+            writes.send(write).ok();
+        }
+    }
+
+    fn flush(&self) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.write(Write::Flush(tx));
+        rx.recv().ok();
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // closing the queue lets the writer commit what's left and stop
+        self.writes.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+impl Write {
+    fn apply(&self, db: &Database) -> Result<(), StoreError> {
+        let tx = db.begin_write()?;
+        match self {
+            Write::Put(table, id, value) => {
+                tx.open_table(*table)?
+                    .insert(id.as_u128(), value.as_slice())?;
+            }
+            Write::Remove(table, id) => {
+                tx.open_table(*table)?.remove(id.as_u128())?;
+            }
+            Write::History {
+                user,
+                completed_at,
+                id,
+                value,
+            } => {
+                tx.open_table(HISTORY)?.insert(
+                    (user.as_str(), *completed_at, id.as_u128()),
+                    value.as_slice(),
+                )?;
+            }
+            Write::Flush(_) => return Ok(()),
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn write(db: &Database, writes: mpsc::Receiver<Write>) {
+    for write in writes {
+        if let Write::Flush(done) = &write {
+            done.send(()).ok();
+            continue;
+        }
+        if let Err(e) = write.apply(db) {
+            match write {
+                Write::Put(table, id, _) => {
+                    warn!(%id, table = %table, error = %e, "Couldn't store entry")
+                }
+                Write::Remove(table, id) => {
+                    warn!(%id, table = %table, error = %e, "Couldn't remove entry")
+                }
+                Write::History { id, .. } => {
+                    warn!(%id, error = %e, "Couldn't store history entry")
+                }
+                Write::Flush(_) => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{history::OutcomeFilter, status::FailureReason};
+
+    fn transfer() -> StoredTransfer {
+        StoredTransfer {
+            filename: "file.txt".to_owned(),
+            content_type: "text/plain".to_owned(),
+            user: "default".to_owned(),
+            limits: UploadLimits::default(),
+            digest: None,
+            preview: false,
+            declared_type: None,
+            device: None,
+            created_at: 1,
+            expires_at: 42,
+        }
+    }
+
+    #[test]
+    fn stores_and_removes_transfers() {
+        let store = Store::in_memory();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        store.put_transfer(a, &transfer());
+        store.put_transfer(b, &transfer());
+        store.remove_transfer(a);
+
+        let transfers = store.transfers();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].0, b);
+        assert_eq!(transfers[0].1.filename, "file.txt");
+        assert_eq!(transfers[0].1.limits, UploadLimits::default());
+    }
+
+    #[test]
+    fn stores_statuses() {
+        let store = Store::in_memory();
+        let id = Uuid::new_v4();
+        let status = TransferStatus::failed(FailureReason::TooSlow);
+        store.put_status(
+            id,
+            &StoredStatus {
+                status: status.clone(),
+                expires_at: 1,
+            },
+        );
+
+        let statuses = store.statuses();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].0, id);
+        assert_eq!(statuses[0].1.status, status);
+    }
+
+    #[test]
+    fn disabled_store_is_empty() {
+        let store = Store::disabled();
+        store.put_transfer(Uuid::new_v4(), &transfer());
+        assert!(store.transfers().is_empty());
+    }
+
+    fn entry(filename: &str, completed_at: i64, outcome: TransferStatus) -> HistoryEntry {
+        HistoryEntry {
+            id: Uuid::new_v4(),
+            filename: filename.to_owned(),
+            size: 3,
+            created_at: completed_at - 1,
+            completed_at,
+            receiver: None,
+            outcome,
+            declared_type: None,
+            detected_type: None,
+        }
+    }
+
+    #[test]
+    fn history_is_per_user_and_newest_first() {
+        let store = Store::in_memory();
+        let (a, b) = (
+            entry(
+                "a.txt",
+                10,
+                TransferStatus::Completed {
+                    digest: None,
+                    scan: None,
+                },
+            ),
+            entry(
+                "b.txt",
+                20,
+                TransferStatus::Completed {
+                    digest: None,
+                    scan: None,
+                },
+            ),
+        );
+        store.put_history("alice", &a);
+        store.put_history("alice", &b);
+        store.put_history(
+            "bob",
+            &entry(
+                "c.txt",
+                30,
+                TransferStatus::Completed {
+                    digest: None,
+                    scan: None,
+                },
+            ),
+        );
+
+        let page = store.history("alice", &HistoryQuery::default()).unwrap();
+        assert!(!page.more);
+        assert_eq!(page.entries, vec![b, a]);
+    }
+
+    #[test]
+    fn history_is_filtered_and_paginated() {
+        let store = Store::in_memory();
+        let failed = TransferStatus::failed(FailureReason::ReceiverDisconnected);
+        for i in 0..5 {
+            store.put_history("alice", &entry(&format!("file{i}.TXT"), i, failed.clone()));
+        }
+        store.put_history("alice", &entry("other.bin", 10, failed));
+        store.put_history(
+            "alice",
+            &entry(
+                "file.txt",
+                11,
+                TransferStatus::Completed {
+                    digest: None,
+                    scan: None,
+                },
+            ),
+        );
+
+        let query = HistoryQuery {
+            offset: 1,
+            limit: Some(2),
+            outcome: Some(OutcomeFilter::Failed),
+            filename: Some("file".to_owned()),
+            ..Default::default()
+        };
+        let page = store.history("alice", &query).unwrap();
+        assert!(page.more);
+        let names: Vec<_> = page.entries.iter().map(|e| e.filename.as_str()).collect();
+        assert_eq!(names, ["file3.TXT", "file2.TXT"]);
+        let query = HistoryQuery { offset: 3, ..query };
+        let page = store.history("alice", &query).unwrap();
+        assert!(!page.more);
+        let names: Vec<_> = page.entries.iter().map(|e| e.filename.as_str()).collect();
+        assert_eq!(names, ["file1.TXT", "file0.TXT"]);
+
+        let query = HistoryQuery {
+            since: Some(2),
+            until: Some(4),
+            ..Default::default()
+        };
+        let page = store.history("alice", &query).unwrap();
+        let times: Vec<_> = page.entries.iter().map(|e| e.completed_at).collect();
+        assert_eq!(times, [3, 2]);
+        let query = HistoryQuery {
+            since: Some(4),
+            until: Some(2),
+            ..Default::default()
+        };
+        assert!(store.history("alice", &query).unwrap().entries.is_empty());
+    }
+}
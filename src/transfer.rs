@@ -1,105 +1,308 @@
 use crate::{
     bounded_body,
+    checksum::Digest,
+    devices::Offer,
+    history::{ReceiverDetails, TransferRecord},
+    images::Thumbnail,
     jwt::{TransferClaims, TransferRole},
     mutex::MutexExt,
+    progress::{self, ProgressBody, ProgressRx, ProgressTx, TransferProgress},
+    quota::QuotaPermit,
+    status::{FailureReason, TransferStatus},
+    upload::UploadLimits,
+};
+use actix_web::{
+    body::MessageBody, dev::Payload, http::header::ContentEncoding, web, FromRequest, HttpMessage,
+    HttpRequest,
 };
-use actix_web::{body::MessageBody, dev::Payload, web, FromRequest, HttpMessage, HttpRequest};
 use std::{
     collections::HashMap,
-    convert::Infallible,
     future::{ready, Ready},
-    sync::{Arc, Mutex as StdMutex},
+    sync::{Arc, Mutex as StdMutex, OnceLock},
 };
 use tokio::sync::{oneshot, watch};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Transfers(pub Arc<StdMutex<HashMap<Uuid, TransferInfo>>>);
 
 #[derive(Debug)]
 pub enum TransferInfo {
     WaitingForReceiver {
-        filename: String,
+        record: TransferRecord,
         content_type: mime::Mime,
+        limits: UploadLimits,
+        permit: QuotaPermit,
         receiver_tx: watch::Sender<bool>,
         receiver_rx: watch::Receiver<bool>,
+        progress_tx: ProgressTx,
+        progress_rx: ProgressRx,
     },
     WaitingForSender {
+        record: TransferRecord,
         sender: TransferSender,
-        content_length_tx: oneshot::Sender<Option<usize>>,
+        body_info_tx: oneshot::Sender<BodyInfo>,
+        progress_tx: ProgressTx,
+        progress_rx: ProgressRx,
+        limits: UploadLimits,
+        permit: QuotaPermit,
+    },
+    Sending {
+        abort: AbortHandle,
+        progress_tx: ProgressTx,
+        progress_rx: ProgressRx,
+    },
+}
+
+pub const MAX_REASON_LEN: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Abort {
+    Cancelled,
+    Declined(Option<String>),
+}
+
+impl Abort {
+    pub fn message(&self) -> String {
+        match self {
+            Abort::Cancelled => "cancelled by the sender".to_owned(),
+            Abort::Declined(None) => "declined by the receiver".to_owned(),
+            Abort::Declined(Some(reason)) => format!("declined by the receiver: {reason}"),
+        }
+    }
+
+    pub fn status(&self) -> TransferStatus {
+        match self {
+            Abort::Cancelled => TransferStatus::failed(FailureReason::SenderCancelled),
+            Abort::Declined(reason) => TransferStatus::declined(reason.clone()),
+        }
+    }
+}
+
+/// Stops the sender's request, the first [`Abort`] wins.
+#[derive(Debug, Clone, Default)]
+pub struct AbortHandle {
+    token: CancellationToken,
+    abort: Arc<OnceLock<Abort>>,
+}
+
+impl AbortHandle {
+    pub fn abort(&self, abort: Abort) {
+        self.abort.set(abort).ok();
+        self.token.cancel();
+    }
+
+    pub fn reason(&self) -> Option<&Abort> {
+        self.abort.get()
+    }
+
+    pub async fn aborted(&self) -> Abort {
+        self.token.cancelled().await;
+        self.abort.get().cloned().unwrap_or(Abort::Cancelled)
+    }
+}
+
+pub enum Aborted {
+    /// The transfer was removed before the sender started, its receiver's body has to be closed.
+    Pending {
+        record: Box<TransferRecord>,
+        sender: Option<TransferSender>,
     },
+    Running,
 }
 
-pub type TransferSender = bounded_body::Sender<Infallible>;
+pub type TransferSender = bounded_body::Sender<TransferAborted>;
+
+#[derive(Debug, thiserror::Error)]
+#[error("The transfer was aborted: {0}")]
+pub struct TransferAborted(pub String);
+
+#[derive(Debug, Default, Clone)]
+pub struct BodyInfo {
+    pub content_length: Option<usize>,
+    pub content_encoding: ContentEncoding,
+    /// Replaces the type the transfer was created with.
+    pub content_type: Option<mime::Mime>,
+    pub digest: Option<Digest>,
+    /// The payload is modified on its way, so its length and digest are unknown.
+    pub modified: bool,
+}
 
 pub struct ReceiverInfo<B> {
     pub filename: String,
     pub content_type: mime::Mime,
-    pub content_length_rx: oneshot::Receiver<Option<usize>>,
+    pub preview: bool,
+    pub body_info_rx: oneshot::Receiver<BodyInfo>,
     pub body: B,
 }
 
+pub struct PendingTransfer {
+    pub filename: String,
+    pub content_type: mime::Mime,
+    pub thumbnail: Option<Arc<Thumbnail>>,
+}
+
 pub struct SenderInfo {
+    pub record: TransferRecord,
     pub sender: TransferSender,
-    pub content_length_tx: oneshot::Sender<Option<usize>>,
+    pub body_info_tx: Option<oneshot::Sender<BodyInfo>>,
+    pub limits: UploadLimits,
+    pub permit: QuotaPermit,
+    pub abort: AbortHandle,
+}
+
+impl TransferInfo {
+    /// The record of a transfer that's not sending yet, the sender's request owns it afterwards.
+    pub fn record(&self) -> Option<&TransferRecord> {
+        match self {
+            TransferInfo::WaitingForReceiver { record, .. }
+            | TransferInfo::WaitingForSender { record, .. } => Some(record),
+            TransferInfo::Sending { .. } => None,
+        }
+    }
 }
 
 impl Transfers {
     pub fn new() -> Self {
         Self(Arc::new(StdMutex::new(HashMap::new())))
     }
-    pub fn new_transfer(&self, filename: String, content_type: mime::Mime) -> Uuid {
-        let id = Uuid::new_v4();
+
+    pub fn insert_transfer(
+        &self,
+        id: Uuid,
+        record: TransferRecord,
+        content_type: mime::Mime,
+        limits: UploadLimits,
+        permit: QuotaPermit,
+    ) {
         let (receiver_tx, receiver_rx) = watch::channel(false);
+        let (progress_tx, progress_rx) = progress::channel();
         self.0.always_lock().insert(
             id,
             TransferInfo::WaitingForReceiver {
-                filename,
+                record,
                 content_type,
+                limits,
+                permit,
                 receiver_rx,
                 receiver_tx,
+                progress_tx,
+                progress_rx,
             },
         );
+    }
 
-        id
+    pub fn count(&self) -> usize {
+        self.0.always_lock().len()
     }
 
     pub fn receiver_rx(&self, id: &Uuid) -> Option<watch::Receiver<bool>> {
         match self.0.always_lock().get(id)? {
             TransferInfo::WaitingForReceiver { receiver_rx, .. } => Some(receiver_rx.clone()),
-            TransferInfo::WaitingForSender { .. } => None,
+            TransferInfo::WaitingForSender { .. } | TransferInfo::Sending { .. } => None,
         }
     }
 
-    pub fn receive(&self, id: &Uuid, n_buffers: usize) -> Option<ReceiverInfo<impl MessageBody>> {
-        let mut lock = self.0.always_lock();
-        let transfer = lock.get_mut(id)?;
-        if !matches!(transfer, TransferInfo::WaitingForReceiver { .. }) {
-            return None;
+    pub fn progress_rx(&self, id: &Uuid) -> Option<ProgressRx> {
+        match self.0.always_lock().get(id)? {
+            TransferInfo::WaitingForReceiver { progress_rx, .. }
+            | TransferInfo::WaitingForSender { progress_rx, .. }
+            | TransferInfo::Sending { progress_rx, .. } => Some(progress_rx.clone()),
         }
+    }
 
-        let (sender, body) = bounded_body::new(n_buffers);
-        let (content_length_tx, content_length_rx) = oneshot::channel();
-        let transfer = std::mem::replace(
-            transfer,
-            TransferInfo::WaitingForSender {
-                sender,
-                content_length_tx,
-            },
-        );
-        match transfer {
+    pub fn pending(&self, id: &Uuid) -> Option<PendingTransfer> {
+        match self.0.always_lock().get(id)? {
             TransferInfo::WaitingForReceiver {
-                receiver_tx,
+                record,
                 content_type,
-                filename,
                 ..
-            } => {
+            } => Some(PendingTransfer {
+                filename: record.filename.clone(),
+                content_type: content_type.clone(),
+                thumbnail: record.thumbnail.clone(),
+            }),
+            TransferInfo::WaitingForSender { .. } | TransferInfo::Sending { .. } => None,
+        }
+    }
+
+    pub fn offers_for(&self, device: &Uuid) -> Vec<Offer> {
+        self.0
+            .always_lock()
+            .iter()
+            .filter_map(|(id, info)| match info {
+                TransferInfo::WaitingForReceiver {
+                    record,
+                    content_type,
+                    ..
+                } if record.device == Some(*device) => {
+                    Some(Offer::new(*device, *id, record, content_type))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn set_thumbnail(&self, id: &Uuid, thumbnail: Thumbnail) -> bool {
+        match self.0.always_lock().get_mut(id) {
+            Some(TransferInfo::WaitingForReceiver { record, .. }) => {
+                record.thumbnail = Some(Arc::new(thumbnail));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn receive(
+        &self,
+        id: &Uuid,
+        n_buffers: usize,
+        receiver: ReceiverDetails,
+    ) -> Option<ReceiverInfo<impl MessageBody>> {
+        let mut transfers = self.0.always_lock();
+        if !matches!(
+            transfers.get(id),
+            Some(TransferInfo::WaitingForReceiver { .. })
+        ) {
+            return None;
+        }
+
+        match transfers.remove(id) {
+            Some(TransferInfo::WaitingForReceiver {
+                mut record,
+                content_type,
+                limits,
+                permit,
+                receiver_tx,
+                progress_tx,
+                progress_rx,
+                ..
+            }) => {
+                let (sender, body) = bounded_body::new(n_buffers);
+                let (body_info_tx, body_info_rx) = oneshot::channel();
+                let (filename, preview) = (record.filename.clone(), record.preview);
+                record.receiver = Some(receiver);
+                transfers.insert(
+                    *id,
+                    TransferInfo::WaitingForSender {
+                        record,
+                        sender,
+                        body_info_tx,
+                        progress_tx: progress_tx.clone(),
+                        progress_rx,
+                        limits,
+                        permit,
+                    },
+                );
                 receiver_tx.send(true).ok();
+                progress_tx.send_replace(TransferProgress::ReceiverConnected);
                 Some(ReceiverInfo {
                     filename,
                     content_type,
-                    content_length_rx,
-                    body,
+                    preview,
+                    body_info_rx,
+                    body: ProgressBody::new(body, progress_tx),
                 })
             }
             _ => unreachable!(),
@@ -117,15 +320,100 @@ impl Transfers {
 
         match transfers.remove(id) {
             Some(TransferInfo::WaitingForSender {
+                record,
                 sender,
-                content_length_tx,
-            }) => Some(SenderInfo {
-                content_length_tx,
-                sender,
-            }),
+                body_info_tx,
+                progress_tx,
+                progress_rx,
+                limits,
+                permit,
+            }) => {
+                let abort = AbortHandle::default();
+                transfers.insert(
+                    *id,
+                    TransferInfo::Sending {
+                        abort: abort.clone(),
+                        progress_tx,
+                        progress_rx,
+                    },
+                );
+                Some(SenderInfo {
+                    record,
+                    body_info_tx: Some(body_info_tx),
+                    sender,
+                    limits,
+                    permit,
+                    abort,
+                })
+            }
             _ => unreachable!(),
         }
     }
+
+    pub fn finish(&self, id: &Uuid) {
+        self.0.always_lock().remove(id);
+    }
+
+    pub fn remove_pending(&self, id: &Uuid) -> Option<TransferInfo> {
+        let mut transfers = self.0.always_lock();
+        match transfers.get(id)? {
+            TransferInfo::Sending { .. } => None,
+            _ => transfers.remove(id),
+        }
+    }
+
+    /// Aborts a transfer wherever it is, a receiver declining it is reported to the sender's events.
+    pub fn abort(&self, id: &Uuid, abort: Abort) -> Option<Aborted> {
+        let mut transfers = self.0.always_lock();
+        let progress = match &abort {
+            Abort::Cancelled => None,
+            Abort::Declined(reason) => Some(TransferProgress::ReceiverDeclined {
+                reason: reason.clone(),
+            }),
+        };
+        let report = |progress_tx: &ProgressTx| {
+            if let Some(progress) = progress {
+                progress_tx.send_replace(progress);
+            }
+        };
+        match transfers.get(id)? {
+            TransferInfo::Sending {
+                abort: handle,
+                progress_tx,
+                ..
+            } => {
+                report(progress_tx);
+                handle.abort(abort);
+                Some(Aborted::Running)
+            }
+            _ => match transfers.remove(id)? {
+                TransferInfo::WaitingForReceiver {
+                    record,
+                    progress_tx,
+                    ..
+                } => {
+                    report(&progress_tx);
+                    Some(Aborted::Pending {
+                        record: Box::new(record),
+                        sender: None,
+                    })
+                }
+                TransferInfo::WaitingForSender {
+                    record,
+                    sender,
+                    progress_tx,
+                    ..
+                } => {
+                    report(&progress_tx);
+                    Some(Aborted::Pending {
+                        record: Box::new(record),
+                        sender: Some(sender),
+                    })
+                }
+                TransferInfo::Sending { .. } => unreachable!(),
+            },
+        }
+    }
 }
 
 pub struct SendTransfer(pub SenderInfo);
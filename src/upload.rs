@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::{num::NonZeroU64, time::Duration};
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+const MAX_PAUSE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UploadLimits {
+    pub idle_timeout: Duration,
+    pub max_duration: Option<Duration>,
+    /// Only checked once the upload ran for longer than `idle_timeout`.
+    pub min_throughput: Option<u64>,
+}
+
+/// Per-transfer overrides of the server's [`UploadLimits`], they can only tighten them.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadLimitsOverride {
+    /// In seconds.
+    #[serde(default)]
+    #[schema(value_type = Option<u64>, minimum = 1)]
+    pub idle_timeout: Option<NonZeroU64>,
+    /// In seconds.
+    #[serde(default)]
+    #[schema(value_type = Option<u64>, minimum = 1)]
+    pub max_duration: Option<NonZeroU64>,
+    /// In bytes per second.
+    #[serde(default)]
+    pub min_throughput: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error, Copy, Clone, Eq, PartialEq)]
+pub enum UploadTimeout {
+    #[error("No data was received for too long")]
+    Idle,
+    #[error("The upload took too long")]
+    MaxDuration,
+    #[error("The upload was too slow")]
+    TooSlow,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(10),
+            max_duration: None,
+            min_throughput: None,
+        }
+    }
+}
+
+impl UploadLimits {
+    pub fn restrict(self, with: &UploadLimitsOverride) -> Self {
+        Self {
+            idle_timeout: with
+                .idle_timeout
+                .map(|t| Duration::from_secs(t.get()))
+                .map_or(self.idle_timeout, |t| t.min(self.idle_timeout)),
+            max_duration: min_opt(
+                self.max_duration,
+                with.max_duration.map(|t| Duration::from_secs(t.get())),
+            ),
+            min_throughput: max_opt(self.min_throughput, with.min_throughput),
+        }
+    }
+}
+
+fn min_opt<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn max_opt<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[derive(Debug)]
+pub struct UploadTimer {
+    limits: UploadLimits,
+    started: Instant,
+    last_chunk: Instant,
+    paused_since: Option<Instant>,
+    paused_for: Duration,
+    bytes: u64,
+}
+
+impl UploadTimer {
+    pub fn new(limits: UploadLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            limits,
+            started: now,
+            last_chunk: now,
+            paused_since: None,
+            paused_for: Duration::ZERO,
+            bytes: 0,
+        }
+    }
+
+    fn deadline(&self) -> (Instant, UploadTimeout) {
+        let idle = match self.paused_since {
+            Some(since) => since + MAX_PAUSE,
+            None => self.last_chunk + self.limits.idle_timeout,
+        };
+        match self.limits.max_duration {
+            Some(max) if self.started + max < idle => {
+                (self.started + max, UploadTimeout::MaxDuration)
+            }
+            _ => (idle, UploadTimeout::Idle),
+        }
+    }
+
+    pub fn record(&mut self, len: usize) -> Result<(), UploadTimeout> {
+        let now = Instant::now();
+        self.last_chunk = now;
+        self.bytes += len as u64;
+
+        let active = now
+            .duration_since(self.started)
+            .saturating_sub(self.paused_for);
+        match self.limits.min_throughput {
+            Some(min) if active > self.limits.idle_timeout => {
+                if (self.bytes as f64 / active.as_secs_f64()) < min as f64 {
+                    Err(UploadTimeout::TooSlow)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.paused_since.get_or_insert_with(Instant::now);
+    }
+
+    pub fn resume(&mut self) {
+        if let Some(since) = self.paused_since.take() {
+            let now = Instant::now();
+            self.paused_for += now.duration_since(since);
+            self.last_chunk = now;
+        }
+    }
+
+    pub async fn timeout<F: std::future::Future>(
+        &self,
+        fut: F,
+    ) -> Result<F::Output, UploadTimeout> {
+        let (deadline, reason) = self.deadline();
+        tokio::time::timeout_at(deadline, fut)
+            .await
+            .map_err(|_| reason)
+    }
+}
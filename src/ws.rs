@@ -0,0 +1,199 @@
+use crate::{
+    checksum::Digest,
+    relay::{Controls, Part, Relay, RelayError, Relayed, Upload},
+    scan::ScanVerdict,
+};
+use actix_web::http::header::ContentEncoding;
+use actix_ws::{AggregatedMessage, Session};
+use serde::{Deserialize, Serialize};
+
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ClientMessage {
+    #[serde(rename_all = "camelCase")]
+    Start {
+        content_length: Option<usize>,
+        #[serde(default)]
+        content_encoding: Option<String>,
+        /// Replaces the digest declared when creating the transfer.
+        #[serde(default)]
+        digest: Option<Digest>,
+    },
+    Pause,
+    Resume,
+    /// The digest is verified in addition to the announced one.
+    End {
+        #[serde(default)]
+        digest: Option<Digest>,
+    },
+    Cancel {
+        reason: Option<String>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ServerMessage<'a> {
+    Ack {
+        bytes: u64,
+    },
+    Paused,
+    Resumed,
+    Completed {
+        bytes: u64,
+        digest: &'a Digest,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        scan: Option<&'a ScanVerdict>,
+    },
+    Cancelled {
+        by: CancelledBy,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a str>,
+    },
+    Error {
+        message: &'a str,
+    },
+}
+
+#[derive(Serialize, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+enum CancelledBy {
+    Sender,
+    Receiver,
+}
+
+struct WsUpload<'s> {
+    session: &'s mut Session,
+    stream: actix_ws::AggregatedMessageStream,
+}
+
+impl Upload for WsUpload<'_> {
+    type Message = AggregatedMessage;
+
+    async fn recv(&mut self) -> Result<AggregatedMessage, RelayError> {
+        match self.stream.recv().await {
+            Some(Ok(msg)) => Ok(msg),
+            Some(Err(e)) => Err(RelayError::Protocol(e)),
+            None => Err(RelayError::SenderDisconnected),
+        }
+    }
+
+    async fn handle(
+        &mut self,
+        msg: AggregatedMessage,
+        controls: Controls<'_>,
+    ) -> Result<Option<Part>, RelayError> {
+        let text = match msg {
+            AggregatedMessage::Binary(buf) => return Ok(Some(Part::Chunk(buf))),
+            AggregatedMessage::Text(text) => text,
+            AggregatedMessage::Ping(msg) => {
+                self.session
+                    .pong(&msg)
+                    .await
+                    .map_err(|_| RelayError::SenderDisconnected)?;
+                return Ok(None);
+            }
+            AggregatedMessage::Pong(_) => return Ok(None),
+            AggregatedMessage::Close(_) => return Err(RelayError::SenderDisconnected),
+        };
+        match serde_json::from_str(&text).map_err(|e| RelayError::BadMessage(e.to_string()))? {
+            ClientMessage::Start {
+                content_length,
+                content_encoding,
+                digest,
+            } => {
+                if let Some(len) = content_length {
+                    controls.permit.check_size(len as u64)?;
+                }
+                let content_encoding = match content_encoding {
+                    Some(enc) => enc.parse().map_err(|_| {
+                        RelayError::BadMessage(format!("unsupported encoding {enc}"))
+                    })?,
+                    None => ContentEncoding::Identity,
+                };
+                let info = controls.info;
+                if let Some(digest) = digest {
+                    let digest = digest
+                        .supported()
+                        .map_err(|e| RelayError::BadMessage(e.to_string()))?;
+                    info.digest = Some(digest);
+                }
+                // sent with the first frame, once the payload's type is known
+                info.content_length = content_length;
+                info.content_encoding = content_encoding;
+            }
+            ClientMessage::Pause => {
+                controls.timer.pause();
+                send_message(self.session, &ServerMessage::Paused).await?;
+            }
+            ClientMessage::Resume => {
+                controls.timer.resume();
+                send_message(self.session, &ServerMessage::Resumed).await?;
+            }
+            ClientMessage::End { digest } => return Ok(Some(Part::End { digest })),
+            ClientMessage::Cancel { reason } => return Err(RelayError::Cancelled(reason)),
+        }
+        Ok(None)
+    }
+
+    async fn relayed(&mut self, bytes: u64) -> Result<(), RelayError> {
+        send_message(self.session, &ServerMessage::Ack { bytes }).await
+    }
+}
+
+/// Every frame is acknowledged once the receiver's body accepted it,
+/// so the sender can limit the amount of data in flight.
+pub async fn relay(
+    mut session: Session,
+    stream: actix_ws::MessageStream,
+    relay: Relay<'_>,
+) -> Result<Relayed, RelayError> {
+    let stream = stream
+        .max_frame_size(MAX_FRAME_SIZE)
+        .aggregate_continuations()
+        .max_continuation_size(MAX_FRAME_SIZE);
+
+    let res = relay
+        .run(&mut WsUpload {
+            session: &mut session,
+            stream,
+        })
+        .await;
+    let message = res.as_ref().err().map(ToString::to_string);
+    let msg = match &res {
+        Ok(relayed) => ServerMessage::Completed {
+            bytes: relayed.bytes,
+            digest: &relayed.digest,
+            scan: relayed.verdict.as_ref(),
+        },
+        Err(RelayError::ReceiverDisconnected) => ServerMessage::Cancelled {
+            by: CancelledBy::Receiver,
+            reason: None,
+        },
+        Err(RelayError::Declined(reason)) => ServerMessage::Cancelled {
+            by: CancelledBy::Receiver,
+            reason: reason.as_deref(),
+        },
+        Err(RelayError::Cancelled(reason)) => ServerMessage::Cancelled {
+            by: CancelledBy::Sender,
+            reason: reason.as_deref(),
+        },
+        Err(_) => ServerMessage::Error {
+            message: message.as_deref().unwrap_or_default(),
+        },
+    };
+    send_message(&mut session, &msg).await.ok();
+    session.close(None).await.ok();
+
+    res
+}
+
+async fn send_message(session: &mut Session, msg: &ServerMessage<'_>) -> Result<(), RelayError> {
+    let text = serde_json::to_string(msg).map_err(|e| RelayError::BadMessage(e.to_string()))?;
+    session
+        .text(text)
+        .await
+        .map_err(|_| RelayError::SenderDisconnected)
+}
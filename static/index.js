@@ -8,6 +8,16 @@ const filename = document.getElementById('filename');
 const setTokenForm = document.getElementById('set-token');
 /** {@type {HTMLInputElement}} */
 const setTokenInput = document.getElementById('token');
+/** {@type {HTMLInputElement}} */
+const previewInput = document.getElementById('preview');
+/** {@type {HTMLButtonElement}} */
+const showHistoryButton = document.getElementById('show-history');
+/** {@type {HTMLSelectElement}} */
+const deviceSelect = document.getElementById('device');
+/** {@type {HTMLButtonElement}} */
+const showDevicesButton = document.getElementById('show-devices');
+/** {@type {HTMLParagraphElement}} */
+const inboxBanner = document.getElementById('inbox');
 
 function updateFileName() {
     if (fileInput.files.length > 0) {
@@ -19,6 +29,7 @@ function updateToken() {
     if (localStorage.getItem('frachter-token')) {
         fileForm.classList.remove('hidden');
         setTokenForm.classList.add('hidden');
+        loadDevices().catch(() => {});
     } else {
         fileForm.classList.add('hidden');
         setTokenForm.classList.remove('hidden');
@@ -39,10 +50,14 @@ fileForm.addEventListener('submit', async (e) => {
     e.preventDefault();
     if (fileInput.files.length <= 0) return;
 
-    await sendFile(fileInput.files[0]);
+    const device = deviceSelect.value ? deviceSelect.selectedOptions[0] : null;
+    await sendFile(fileInput.files[0], previewInput.checked, device && {id: device.value, name: device.text});
     //setTimeout(() => ov.remove(), 2000);
 });
 
+showHistoryButton.addEventListener('click', () => showHistory());
+showDevicesButton.addEventListener('click', () => showDevices());
+
 setTokenForm.addEventListener('submit', (e) => {
     e.preventDefault();
     if (!setTokenInput.value) return;
@@ -53,6 +68,15 @@ setTokenForm.addEventListener('submit', (e) => {
 updateToken();
 updateFileName();
 
+// a device opening a pairing link is paired, paired devices receive what's sent to them
+const pairCode = new URLSearchParams(location.search).get('pair');
+if (pairCode) {
+    history.replaceState(null, '', location.pathname);
+    pairDevice(pairCode).then(openInbox);
+} else {
+    openInbox();
+}
+
 function createOverlay({title, content}) {
     const overlay = document.createElement('div');
     overlay.classList.add('overlay');
@@ -170,54 +194,110 @@ function createTransferring() {
 /**
  *
  * @param {File} file
+ * @param {boolean} preview
+ * @param {{id: string, name: string} | null} device the paired device to send to, or null to show a QR code
  * @returns {Promise<void>}
  */
-async function sendFile(file) {
+async function sendFile(file, preview, device) {
     const overlay = createOverlay({title: 'Creating Transfer...', content: createLoader()});
+    let events;
+    // closing the page cancels the transfer instead of leaving it open until it expires
+    const cancel = () => fetch('api/v1/transfer', {method: 'DELETE', keepalive: true, headers: makeHeaders()});
     try {
-        const res = await fetch('/api/transfers', {
+        const res = await fetch('api/v1/transfers', {
             method: 'PUT',
             body: JSON.stringify({
                 filename: file.name,
                 contentType: file.type,
+                preview,
+                device: device?.id,
             }),
             headers: makeHeaders({'content-type': 'application/json'}),
         });
         const json = await tryJson(res);
-
-        const recvUrl = `${location.origin}/api/receive/${json.id}`;
-        overlay.update({title: 'Waiting for peer...', content: await createWaiting(recvUrl)});
-        await waitForPeer();
+        window.addEventListener('pagehide', cancel);
+        // the receiver sees the thumbnail while waiting, it's fine if the server can't make one
+        if (file.type.startsWith('image/')) uploadThumbnail(file).catch(() => {});
+
+        events = new EventSource('api/v1/transfer/events');
+                const title = device ? `Waiting for ${device.name}...` : 'Waiting for peer...';
+        overlay.update({title, content: await createWaiting(json.url)});
+        await waitForPeer(events);
         const [content, progressCb] = createTransferring();
         overlay.update({title: 'Sending...', content});
-        await transfer(file, progressCb);
+        const send = 'WebSocket' in window ? transferWs : transfer;
+        await Promise.all([send(file), waitForDelivery(events, file.size, progressCb)]);
         overlay.remove();
     } catch (e) {
         overlay.update({title: 'Error', content: createError(e.toString(), () => overlay.remove())})
+    } finally {
+        window.removeEventListener('pagehide', cancel);
+        events?.close();
     }
 }
 
-async function waitForPeer() {
-    while (true) {
-        const res = await fetch('/api/transfer/wait', {
-            headers: makeHeaders(),
+/**
+ * @param {File} file
+ * @returns {Promise<void>}
+ */
+async function uploadThumbnail(file) {
+    await tryJson(await fetch('api/v1/transfer/thumbnail', {
+        method: 'POST',
+        body: file,
+        headers: makeHeaders(),
+    }));
+}
+
+/**
+ * @param {EventSource} events
+ * @returns {Promise<void>}
+ */
+function waitForPeer(events) {
+    return new Promise((resolve, reject) => {
+        events.addEventListener('receiverConnected', () => resolve(), {once: true});
+        events.addEventListener('receiverDeclined', ({data}) => reject(declinedError(data)), {once: true});
+        events.addEventListener('error', () => {
+            if (events.readyState === EventSource.CLOSED) {
+                reject(new Error('Lost connection to the server'));
+            }
         });
-        if (res.ok) {
-            return;
-        }
-        const json = await res.json();
-        if (res.status !== 504) {
-            throw new Error(`${res.status} ${res.statusText} - ${json?.message ?? JSON.stringify(json)}`);
-        }
-    }
+    });
 }
 
-function transfer(file, progressCb) {
+/**
+ * @param {EventSource} events
+ * @param {number} total
+ * @param {(n: number) => void} progressCb
+ * @returns {Promise<void>}
+ */
+function waitForDelivery(events, total, progressCb) {
     return new Promise((resolve, reject) => {
-        const xhr = new XMLHttpRequest();
-        xhr.addEventListener('progress', ({loaded, total}) => {
-            progressCb(loaded / total);
+        events.addEventListener('delivered', ({data}) => {
+            progressCb(JSON.parse(data).bytes / total);
+        });
+        events.addEventListener('completed', () => {
+            progressCb(1);
+            resolve();
         });
+        events.addEventListener('receiverDisconnected', () => {
+            reject(new Error('The receiver disconnected'));
+        });
+        events.addEventListener('receiverDeclined', ({data}) => reject(declinedError(data)));
+    });
+}
+
+/**
+ * @param {string} data the `receiverDeclined` event's data
+ * @returns {Error}
+ */
+function declinedError(data) {
+    const {reason} = JSON.parse(data);
+    return new Error(reason ? `The receiver declined the transfer: ${reason}` : 'The receiver declined the transfer');
+}
+
+function transfer(file) {
+    return new Promise((resolve, reject) => {
+        const xhr = new XMLHttpRequest();
         xhr.addEventListener('load', () => {
             if (xhr.status >= 200 && xhr.status < 300) {
                 resolve();
@@ -231,12 +311,383 @@ function transfer(file, progressCb) {
         xhr.addEventListener('abort', () => {
             reject(new Error("Aborted"));
         });
-        xhr.open('POST', '/api/transfer/send');
+        xhr.open('POST', 'api/v1/transfer/send');
         xhr.setRequestHeader('x-frachter-token', localStorage.getItem('frachter-token'));
         xhr.send(file);
     });
 }
 
+const WS_CHUNK_SIZE = 64 * 1024;
+const WS_MAX_IN_FLIGHT = 8 * WS_CHUNK_SIZE;
+
+/**
+ * Sends the file over a WebSocket, keeping at most {@link WS_MAX_IN_FLIGHT} bytes unacknowledged.
+ *
+ * @param {File} file
+ * @returns {Promise<void>}
+ */
+function transferWs(file) {
+    return new Promise((resolve, reject) => {
+        const ws = new WebSocket(new URL('api/v1/transfer/ws', location.href.replace(/^http/, 'ws')));
+        let sent = 0;
+        let acked = 0;
+        let ended = false;
+
+        const pump = () => {
+            while (sent < file.size && sent - acked < WS_MAX_IN_FLIGHT) {
+                const chunk = file.slice(sent, sent + WS_CHUNK_SIZE);
+                sent += chunk.size;
+                ws.send(chunk);
+            }
+            if (sent >= file.size && !ended) {
+                ended = true;
+                ws.send(JSON.stringify({type: 'end'}));
+            }
+        };
+
+        ws.addEventListener('open', () => {
+            ws.send(JSON.stringify({type: 'start', contentLength: file.size}));
+            pump();
+        });
+        ws.addEventListener('message', ({data}) => {
+            const msg = JSON.parse(data);
+            switch (msg.type) {
+                case 'ack':
+                    acked = msg.bytes;
+                    pump();
+                    break;
+                case 'completed':
+                    resolve();
+                    break;
+                case 'cancelled':
+                    reject(new Error(`The ${msg.by} cancelled the transfer${msg.reason ? `: ${msg.reason}` : ''}`));
+                    break;
+                case 'error':
+                    reject(new Error(msg.message));
+                    break;
+            }
+        });
+        ws.addEventListener('close', () => reject(new Error('Connection closed')));
+    });
+}
+
+/**
+ * Fills the device selection with the user's paired devices.
+ *
+ * @returns {Promise<{id: string, name: string, pairedAt: number}[]>}
+ */
+async function loadDevices() {
+    const devices = await fetchJson('api/v1/devices', {headers: makeHeaders()});
+    const selected = deviceSelect.value;
+    deviceSelect.replaceChildren(
+        new Option('Show a QR code', ''),
+        ...devices.map(d => new Option(d.name, d.id, false, d.id === selected)),
+    );
+    deviceSelect.classList.toggle('hidden', devices.length === 0);
+    return devices;
+}
+
+function showDevices() {
+    const overlay = createOverlay({title: 'Devices', content: createLoader()});
+
+    const load = async () => {
+        try {
+            const devices = await loadDevices();
+            overlay.update({title: 'Devices', content: createDevices(devices, overlay, load)});
+        } catch (e) {
+            overlay.update({title: 'Error', content: createError(e.toString(), () => overlay.remove())});
+        }
+    };
+    load();
+}
+
+/**
+ * @param {{id: string, name: string, pairedAt: number}[]} devices
+ * @param {ReturnType<typeof createOverlay>} overlay
+ * @param {() => void} reload
+ * @returns {HTMLElement}
+ */
+function createDevices(devices, overlay, reload) {
+    const wrap = document.createElement('div');
+    wrap.classList.add('devices');
+
+    if (devices.length === 0) {
+        const empty = document.createElement('p');
+        empty.textContent = 'No paired devices yet';
+        wrap.append(empty);
+    } else {
+        const list = document.createElement('ul');
+        for (const device of devices) {
+            const item = document.createElement('li');
+            const name = document.createElement('span');
+            name.textContent = device.name;
+            name.title = `Paired ${formatTime(device.pairedAt)}`;
+            const remove = document.createElement('button');
+            remove.textContent = 'Remove';
+            remove.addEventListener('click', async () => {
+                await fetch(`api/v1/devices/${device.id}`, {method: 'DELETE', headers: makeHeaders()});
+                reload();
+            });
+            item.append(name, remove);
+            list.append(item);
+        }
+        wrap.append(list);
+    }
+
+    const pair = document.createElement('form');
+    const name = document.createElement('input');
+    name.type = 'text';
+    name.placeholder = 'Device name';
+    name.required = true;
+    const submit = document.createElement('input');
+    submit.type = 'submit';
+    submit.value = 'Pair';
+    pair.append(name, submit);
+    pair.addEventListener('submit', async (e) => {
+        e.preventDefault();
+        try {
+            const {url} = await fetchJson('api/v1/devices/pairings', {
+                method: 'POST',
+                body: JSON.stringify({name: name.value}),
+                headers: makeHeaders({'content-type': 'application/json'}),
+            });
+            // the code can only be used once and expires after a few minutes
+            const content = await createWaiting(url);
+            const done = document.createElement('button');
+            done.textContent = 'Done';
+            done.addEventListener('click', reload);
+            content.append(done);
+            overlay.update({title: `Scan to pair ${name.value}`, content});
+        } catch (e) {
+            overlay.update({title: 'Error', content: createError(e.toString(), reload)});
+        }
+    });
+    wrap.append(pair);
+
+    const close = document.createElement('button');
+    close.textContent = 'Close';
+    close.addEventListener('click', () => overlay.remove());
+    wrap.append(close);
+
+    return wrap;
+}
+
+/**
+ * Pairs this browser with a code from a pairing link, it keeps the device token as a cookie.
+ *
+ * @param {string} code
+ * @returns {Promise<void>}
+ */
+async function pairDevice(code) {
+    const overlay = createOverlay({title: 'Pairing...', content: createLoader()});
+    try {
+        await fetchJson('api/v1/device/pair', {
+            method: 'POST',
+            body: JSON.stringify({code}),
+            headers: {'content-type': 'application/json'},
+        });
+        overlay.remove();
+    } catch (e) {
+        overlay.update({title: 'Error', content: createError(e.toString(), () => overlay.remove())});
+    }
+}
+
+/**
+ * Receives transfers sent to this device, if it's paired.
+ */
+function openInbox() {
+    const inbox = new EventSource('api/v1/device/inbox');
+    const received = new Set();
+    inbox.addEventListener('device', ({data}) => {
+        inboxBanner.textContent = `Receiving as ${JSON.parse(data).name}`;
+        inboxBanner.classList.remove('hidden');
+    });
+    inbox.addEventListener('transfer', ({data}) => {
+        const offer = JSON.parse(data);
+        if (received.has(offer.id)) return;
+        received.add(offer.id);
+        acceptTransfer(offer);
+    });
+    inbox.addEventListener('error', () => {
+        // unpaired devices are rejected, lost connections are retried by the browser
+        if (inbox.readyState === EventSource.CLOSED) inboxBanner.classList.add('hidden');
+    });
+}
+
+/**
+ * Asks whether to receive a transfer, declining it tells the sender why.
+ *
+ * @param {{id: string, filename: string, preview: boolean}} offer
+ */
+function acceptTransfer(offer) {
+    const wrap = document.createElement('div');
+    wrap.classList.add('error');
+    const text = document.createElement('h4');
+    text.textContent = offer.filename;
+    const receive = document.createElement('button');
+    receive.textContent = 'Receive';
+    const decline = document.createElement('button');
+    decline.textContent = 'Decline';
+    wrap.append(text, receive, decline);
+    requestAnimationFrame(() => receive.focus());
+
+    const overlay = createOverlay({title: 'Incoming Transfer', content: wrap});
+    receive.addEventListener('click', () => {
+        overlay.remove();
+        receiveTransfer(offer);
+    });
+    decline.addEventListener('click', async () => {
+        const reason = prompt('Reason (optional)', '');
+        if (reason === null) return;
+        overlay.remove();
+        await fetch(`api/v1/receive/${offer.id}/decline`, {
+            method: 'POST',
+            body: JSON.stringify({reason: reason.slice(0, 200)}),
+            headers: {'content-type': 'application/json'},
+        }).catch(() => {});
+    });
+}
+
+/**
+ * @param {{id: string, filename: string, preview: boolean}} offer
+ */
+function receiveTransfer(offer) {
+    const link = document.createElement('a');
+    if (offer.preview) {
+        link.href = `api/v1/receive/${offer.id}/view`;
+        link.target = '_blank';
+    } else {
+        link.href = `api/v1/receive/${offer.id}`;
+        link.download = offer.filename;
+    }
+    link.click();
+}
+
+const HISTORY_PAGE_SIZE = 20;
+
+function showHistory() {
+    const overlay = createOverlay({title: 'History', content: createLoader()});
+    const query = {offset: 0, outcome: '', filename: ''};
+
+    const close = document.createElement('button');
+    close.textContent = 'Close';
+    close.addEventListener('click', () => overlay.remove());
+
+    const load = async () => {
+        try {
+            const params = new URLSearchParams({offset: query.offset, limit: HISTORY_PAGE_SIZE});
+            if (query.outcome) params.set('outcome', query.outcome);
+            if (query.filename) params.set('filename', query.filename);
+            const res = await fetch(`api/v1/history?${params}`, {headers: makeHeaders()});
+            const json = await res.json();
+            if (!res.ok) throw new Error(`${res.status} ${res.statusText} - ${json.error ?? JSON.stringify(json)}`);
+
+            overlay.update({title: 'History', content: createHistory(json, query, load, close)});
+        } catch (e) {
+            overlay.update({title: 'Error', content: createError(e.toString(), () => overlay.remove())});
+        }
+    };
+    load();
+}
+
+/**
+ * @param {{entries: object[], more: boolean}} page
+ * @param {{offset: number, outcome: string, filename: string}} query
+ * @param {() => void} reload
+ * @param {HTMLElement} close
+ * @returns {HTMLElement}
+ */
+function createHistory(page, query, reload, close) {
+    const wrap = document.createElement('div');
+    wrap.classList.add('history');
+
+    const filters = document.createElement('form');
+    filters.classList.add('history-filters');
+    const filename = document.createElement('input');
+    filename.type = 'text';
+    filename.placeholder = 'Filename';
+    filename.value = query.filename;
+    const outcome = document.createElement('select');
+    for (const [value, label] of [['', 'All'], ['completed', 'Completed'], ['failed', 'Failed']]) {
+        outcome.append(new Option(label, value, false, value === query.outcome));
+    }
+    const apply = document.createElement('input');
+    apply.type = 'submit';
+    apply.value = 'Filter';
+    filters.append(filename, outcome, apply);
+    filters.addEventListener('submit', (e) => {
+        e.preventDefault();
+        Object.assign(query, {offset: 0, outcome: outcome.value, filename: filename.value});
+        reload();
+    });
+    wrap.append(filters);
+
+    if (page.entries.length === 0) {
+        const empty = document.createElement('p');
+        empty.textContent = 'No transfers yet';
+        wrap.append(empty);
+    } else {
+        const table = document.createElement('table');
+        const head = table.createTHead().insertRow();
+        for (const title of ['File', 'Size', 'Created', 'Completed', 'Receiver', 'Outcome']) {
+            head.append(Object.assign(document.createElement('th'), {textContent: title}));
+        }
+        const body = table.createTBody();
+        for (const entry of page.entries) {
+            const row = body.insertRow();
+            const receiver = entry.receiver ? [entry.receiver.ip, entry.receiver.userAgent].filter(Boolean).join(' - ') : '-';
+            const outcome = entry.outcome.status === 'completed' ? 'Completed' : `Failed (${entry.outcome.reason})`;
+            for (const text of [entry.filename, formatBytes(entry.size), formatTime(entry.createdAt), formatTime(entry.completedAt), receiver, outcome]) {
+                row.insertCell().textContent = text;
+            }
+            row.classList.toggle('failed', entry.outcome.status !== 'completed');
+        }
+        wrap.append(table);
+    }
+
+    const nav = document.createElement('div');
+    nav.classList.add('history-nav');
+    const prev = document.createElement('button');
+    prev.textContent = 'Newer';
+    prev.disabled = query.offset === 0;
+    prev.addEventListener('click', () => {
+        query.offset = Math.max(0, query.offset - HISTORY_PAGE_SIZE);
+        reload();
+    });
+    const next = document.createElement('button');
+    next.textContent = 'Older';
+    next.disabled = !page.more;
+    next.addEventListener('click', () => {
+        query.offset += HISTORY_PAGE_SIZE;
+        reload();
+    });
+    nav.append(prev, close, next);
+    wrap.append(nav);
+
+    return wrap;
+}
+
+function formatBytes(n) {
+    const units = ['B', 'KiB', 'MiB', 'GiB', 'TiB'];
+    let unit = 0;
+    while (n >= 1024 && unit < units.length - 1) {
+        n /= 1024;
+        unit++;
+    }
+    return `${n.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+}
+
+function formatTime(unix) {
+    return new Date(unix * 1000).toLocaleString();
+}
+
+async function fetchJson(input, init) {
+    const res = await fetch(input, init);
+    const json = await res.json();
+    if (!res.ok) throw new Error(`${res.status} ${res.statusText} - ${json.error ?? JSON.stringify(json)}`);
+    return json;
+}
+
 async function tryJson(res) {
     const json = res.headers.get('content-type').startsWith('application/json') ? await res.json() : await res.text();
     if (!res.ok || !json.id) {
@@ -0,0 +1,252 @@
+//! An in-process server with helpers for the steps of a transfer.
+
+use actix_http::{Payload, Request};
+use actix_web::{
+    body::{self, BoxBody, MessageBody},
+    cookie::Cookie,
+    dev::{Service, ServiceResponse},
+    error::PayloadError,
+    http::{header, StatusCode},
+    test::{self, TestRequest},
+    web::Bytes,
+};
+use frachter::{
+    config::Config,
+    jwt::{self, TransferClaims, TransferRole},
+    Frachter,
+};
+use futures::{future::LocalBoxFuture, Stream, StreamExt};
+use jsonwebtoken::{EncodingKey, Header};
+use serde_json::Value;
+use std::rc::Rc;
+use uuid::Uuid;
+
+pub const TOKEN: &str = "token";
+const JWT_SECRET: &str = "c2VjcmV0c2VjcmV0";
+
+/// The config the harness starts from, with the token and JWT secret it uses.
+pub fn config(extra: &str) -> Config {
+    toml::from_str(&format!(
+        "bind = \"127.0.0.1:0\"\ntoken = \"{TOKEN}\"\njwt-secret = \"{JWT_SECRET}\"\n{extra}"
+    ))
+    .unwrap()
+}
+
+type Call = dyn Fn(Request) -> LocalBoxFuture<'static, Result<ServiceResponse, actix_web::Error>>;
+
+pub struct Harness {
+    app: Box<Call>,
+    /// The admin listener's app.
+    admin: Box<Call>,
+}
+
+/// A created transfer as the sender knows it.
+pub struct Sender {
+    pub id: Uuid,
+    pub cookie: Cookie<'static>,
+}
+
+/// The status and body of a response, errors of middleware become responses too.
+pub struct Response {
+    pub status: StatusCode,
+    pub body: Bytes,
+}
+
+impl Response {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+
+    /// The message of an error response.
+    pub fn error(&self) -> String {
+        self.json()["error"].as_str().unwrap().to_owned()
+    }
+}
+
+/// Starts a server with `extra` appended to the [`config`].
+pub async fn start(extra: &str) -> Harness {
+    let frachter = Frachter::builder().config(config(extra)).build().unwrap();
+    Harness {
+        app: boxed(test::init_service(frachter.app()).await),
+        admin: boxed(test::init_service(frachter.admin_app()).await),
+    }
+}
+
+fn boxed<S, B>(app: S) -> Box<Call>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    let app = Rc::new(app);
+    Box::new(move |req| {
+        let app = app.clone();
+        Box::pin(async move {
+            test::try_call_service(&*app, req)
+                .await
+                .map(ServiceResponse::map_into_boxed_body)
+        })
+    })
+}
+
+impl Harness {
+    /// Calls the app, the body is read completely.
+    pub async fn call(&self, req: Request) -> Response {
+        let res = self.call_streaming(req).await;
+        let status = res.status();
+        Response {
+            status,
+            body: body::to_bytes(res.into_body()).await.unwrap(),
+        }
+    }
+
+    /// Calls the app, the body can be read while it's still being sent.
+    pub async fn call_streaming(&self, req: Request) -> ServiceResponse<BoxBody> {
+        match (self.app)(req).await {
+            Ok(res) => res,
+            Err(e) => ServiceResponse::from_err(e, TestRequest::default().to_http_request()),
+        }
+    }
+
+    /// The admin listener's `/metrics`.
+    pub async fn metrics(&self) -> String {
+        let res = (self.admin)(TestRequest::get().uri("/metrics").to_request())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        String::from_utf8(test::read_body(res).await.to_vec()).unwrap()
+    }
+
+    pub async fn create(&self, body: Value) -> Sender {
+        let res = self
+            .call_streaming(
+                TestRequest::put()
+                    .uri("/api/v1/transfers")
+                    .insert_header(("x-frachter-token", TOKEN))
+                    .set_json(body)
+                    .to_request(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == "frachter-transfer")
+            .unwrap()
+            .into_owned();
+        let json: Value = test::read_body_json(res).await;
+        Sender {
+            id: json["id"].as_str().unwrap().parse().unwrap(),
+            cookie,
+        }
+    }
+
+    /// A request of the sender, authenticated with the token and the transfer's cookie.
+    pub fn sender_request(&self, sender: &Sender, req: TestRequest) -> TestRequest {
+        req.insert_header(("x-frachter-token", TOKEN))
+            .cookie(sender.cookie.clone())
+    }
+
+    /// Waits for a receiver, resolves with the status of `/api/v1/transfer/wait`.
+    pub async fn wait(&self, sender: &Sender) -> Response {
+        self.call(
+            self.sender_request(sender, TestRequest::get().uri("/api/v1/transfer/wait"))
+                .to_request(),
+        )
+        .await
+    }
+
+    /// Starts receiving, resolves once the sender started sending.
+    pub async fn receive(&self, id: Uuid) -> ServiceResponse<BoxBody> {
+        self.call_streaming(
+            TestRequest::get()
+                .uri(&format!("/api/v1/receive/{id}"))
+                .to_request(),
+        )
+        .await
+    }
+
+    pub async fn send(&self, sender: &Sender, payload: impl Into<Bytes>) -> Response {
+        self.call(
+            self.sender_request(sender, TestRequest::post().uri("/api/v1/transfer/send"))
+                .set_payload(payload)
+                .to_request(),
+        )
+        .await
+    }
+
+    /// Sends a payload that's streamed, e.g. to stall the upload.
+    pub async fn send_stream(
+        &self,
+        sender: &Sender,
+        payload: impl Stream<Item = Result<Bytes, PayloadError>> + 'static,
+    ) -> Response {
+        let req = self
+            .sender_request(sender, TestRequest::post().uri("/api/v1/transfer/send"))
+            .to_request();
+        let (req, _) = req.replace_payload(Payload::Stream {
+            payload: payload.boxed_local(),
+        });
+        self.call(req).await
+    }
+
+    /// Opens the transfer's event stream, its body ends with the final event.
+    pub async fn events(&self, sender: &Sender) -> ServiceResponse<BoxBody> {
+        self.call_streaming(
+            TestRequest::get()
+                .uri("/api/v1/transfer/events")
+                .cookie(sender.cookie.clone())
+                .to_request(),
+        )
+        .await
+    }
+
+    pub async fn cancel(&self, sender: &Sender) -> Response {
+        self.call(
+            self.sender_request(sender, TestRequest::delete().uri("/api/v1/transfer"))
+                .to_request(),
+        )
+        .await
+    }
+
+    /// Declines a transfer as its receiver.
+    pub async fn decline(&self, id: Uuid, body: Value) -> Response {
+        self.call(
+            TestRequest::post()
+                .uri(&format!("/api/v1/receive/{id}/decline"))
+                .set_json(body)
+                .to_request(),
+        )
+        .await
+    }
+
+    pub async fn status(&self, sender: &Sender) -> Response {
+        self.call(
+            self.sender_request(sender, TestRequest::get().uri("/api/v1/transfer/status"))
+                .to_request(),
+        )
+        .await
+    }
+}
+
+/// A transfer cookie signed with the harness' secret.
+pub fn transfer_cookie(
+    role: TransferRole,
+    id: Uuid,
+    expires_at: time::OffsetDateTime,
+) -> Cookie<'static> {
+    let config = (
+        EncodingKey::from_base64_secret(JWT_SECRET).unwrap(),
+        Header::default(),
+    );
+    let token = jwt::encode_token(&config, &TransferClaims::new(role, id, expires_at)).unwrap();
+    Cookie::new("frachter-transfer", token)
+}
+
+/// Reads a response body that's still being sent.
+pub async fn read_body(res: ServiceResponse<BoxBody>) -> Result<Bytes, String> {
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().contains_key(header::CONTENT_DISPOSITION));
+    body::to_bytes(res.into_body())
+        .await
+        .map_err(|e| e.to_string())
+}
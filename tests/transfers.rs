@@ -0,0 +1,502 @@
+mod common;
+
+use actix_web::{
+    body::{self, MessageBody},
+    cookie::SameSite,
+    error::PayloadError,
+    http::{header, StatusCode},
+    test::TestRequest,
+    web::Bytes,
+};
+use common::{read_body, start, transfer_cookie, TOKEN};
+use frachter::jwt::TransferRole;
+use futures::{channel::mpsc, future::poll_fn, join, stream, StreamExt};
+use serde_json::json;
+use std::{pin::Pin, time::Duration};
+
+#[actix_web::test]
+async fn sends_and_receives() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "hello.txt" })).await;
+
+    let (sent, received) = join!(
+        async {
+            assert_eq!(h.wait(&sender).await.status, StatusCode::NO_CONTENT);
+            h.send(&sender, "hello world").await
+        },
+        async { read_body(h.receive(sender.id).await).await },
+    );
+    assert_eq!(sent.status, StatusCode::NO_CONTENT);
+    assert_eq!(received.unwrap(), "hello world");
+
+    let status = h.status(&sender).await;
+    assert_eq!(status.status, StatusCode::OK);
+    assert_eq!(status.json()["status"], "completed");
+    // the transfer is gone once it's done
+    let res = h.receive(sender.id).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn receivers_get_the_digest_sent_with_the_payload() {
+    const DIGEST: &str = "sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:";
+    let h = start("").await;
+    let sender = h
+        .create(json!({ "filename": "hello.txt", "digest": { "sha256": "AAAA" } }))
+        .await;
+
+    let (sent, received) = join!(
+        async {
+            h.wait(&sender).await;
+            let req = TestRequest::post()
+                .uri("/api/v1/transfer/send")
+                .insert_header(("repr-digest", DIGEST))
+                .set_payload("hello world");
+            h.call(h.sender_request(&sender, req).to_request()).await
+        },
+        async {
+            let res = h.receive(sender.id).await;
+            assert_eq!(res.headers().get("repr-digest").unwrap(), DIGEST);
+            read_body(res).await
+        },
+    );
+    assert_eq!(sent.status, StatusCode::NO_CONTENT);
+    assert_eq!(received.unwrap(), "hello world");
+}
+
+#[cfg(not(feature = "blake3"))]
+#[actix_web::test]
+async fn rejects_digests_that_cant_be_verified() {
+    let h = start("").await;
+    let blake3 = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    let res = h
+        .call(
+            TestRequest::put()
+                .uri("/api/v1/transfers")
+                .insert_header(("x-frachter-token", TOKEN))
+                .set_json(json!({ "filename": "a.txt", "digest": { "blake3": blake3 } }))
+                .to_request(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert!(res.error().starts_with("Invalid digest"), "{}", res.error());
+}
+
+#[actix_web::test]
+async fn streams_large_payloads() {
+    const CHUNK: usize = 64 * 1024;
+    const CHUNKS: usize = 256;
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "large.bin" })).await;
+    let chunk = |i: usize| Bytes::from(vec![i as u8; CHUNK]);
+
+    let (sent, received) = join!(
+        async {
+            h.wait(&sender).await;
+            let payload = stream::iter(0..CHUNKS).map(move |i| Ok(chunk(i)));
+            h.send_stream(&sender, payload).await
+        },
+        async { read_body(h.receive(sender.id).await).await },
+    );
+    assert_eq!(sent.status, StatusCode::NO_CONTENT);
+    let received = received.unwrap();
+    assert_eq!(received.len(), CHUNK * CHUNKS);
+    assert!(received
+        .chunks(CHUNK)
+        .enumerate()
+        .all(|(i, c)| c.iter().all(|b| *b == i as u8)));
+}
+
+#[actix_web::test]
+async fn fails_if_the_receiver_disconnects() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.bin" })).await;
+    let (tx, rx) = mpsc::unbounded::<Result<Bytes, PayloadError>>();
+
+    let (sent, _) = join!(
+        async {
+            h.wait(&sender).await;
+            h.send_stream(&sender, rx).await
+        },
+        async {
+            tx.unbounded_send(Ok(Bytes::from_static(b"first"))).unwrap();
+            let res = h.receive(sender.id).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            drop(res);
+            // the sender notices once it has more to send
+            for _ in 0..4 {
+                tx.unbounded_send(Ok(Bytes::from(vec![0; 64 * 1024]))).ok();
+                actix_web::rt::task::yield_now().await;
+            }
+        },
+    );
+    assert_eq!(sent.status, StatusCode::BAD_REQUEST);
+    assert_eq!(sent.error(), "The receiver disconnected");
+    assert_eq!(
+        h.status(&sender).await.json()["reason"],
+        "receiverDisconnected"
+    );
+}
+
+#[actix_web::test]
+async fn streams_events_opened_while_sending() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.bin" })).await;
+    let (tx, rx) = mpsc::unbounded::<Result<Bytes, PayloadError>>();
+    tx.unbounded_send(Ok(Bytes::from_static(b"first"))).unwrap();
+
+    let (sent, (received, events)) = join!(
+        async {
+            h.wait(&sender).await;
+            h.send_stream(&sender, rx).await
+        },
+        async {
+            let res = h.receive(sender.id).await;
+            // the sender is sending already, e.g. the page was reloaded
+            let events = h.events(&sender).await;
+            assert_eq!(events.status(), StatusCode::OK);
+            tx.unbounded_send(Ok(Bytes::from_static(b" second")))
+                .unwrap();
+            tx.close_channel();
+            join!(read_body(res), body::to_bytes(events.into_body()))
+        },
+    );
+    assert_eq!(sent.status, StatusCode::NO_CONTENT);
+    assert_eq!(received.unwrap(), "first second");
+    let events = String::from_utf8(events.unwrap().to_vec()).unwrap();
+    assert!(events.contains("event: completed"), "{events}");
+}
+
+#[actix_web::test]
+async fn rejects_events_from_other_origins() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.txt" })).await;
+    assert_eq!(sender.cookie.same_site(), Some(SameSite::Strict));
+    let events = |origin: &str| {
+        TestRequest::get()
+            .uri("/api/v1/transfer/events")
+            .insert_header((header::ORIGIN, origin))
+            .cookie(sender.cookie.clone())
+            .to_request()
+    };
+
+    let res = h.call(events("https://evil.example")).await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    assert_eq!(res.error(), "Requests from other origins aren't allowed");
+    let res = h.call_streaming(events("http://localhost:8080")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn holds_back_payloads_until_they_are_scanned() {
+    let h = start(
+        r#"[scan]
+command = ["sh", "-c", "if grep -q EICAR \"$1\"; then echo EICAR; exit 1; fi", "sh"]"#,
+    )
+    .await;
+    let sender = h.create(json!({ "filename": "a.txt" })).await;
+    let chunks = ["an EICAR test", " and more"].map(|c| Ok(Bytes::from_static(c.as_bytes())));
+
+    let (sent, first) = join!(
+        async {
+            h.wait(&sender).await;
+            h.send_stream(&sender, stream::iter(chunks)).await
+        },
+        async {
+            let mut body = h.receive(sender.id).await.into_body();
+            poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await
+        },
+    );
+    assert_eq!(sent.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(h.status(&sender).await.json()["reason"], "infected");
+    // not even the first chunk reached the receiver
+    assert!(matches!(first, Some(Err(_))));
+
+    let sender = h.create(json!({ "filename": "b.txt" })).await;
+    let chunks = ["harm", "less"].map(|c| Ok(Bytes::from_static(c.as_bytes())));
+    let (sent, received) = join!(
+        async {
+            h.wait(&sender).await;
+            h.send_stream(&sender, stream::iter(chunks)).await
+        },
+        async { read_body(h.receive(sender.id).await).await },
+    );
+    assert_eq!(sent.status, StatusCode::NO_CONTENT);
+    assert_eq!(received.unwrap(), "harmless");
+}
+
+#[actix_web::test]
+async fn times_out_stalled_uploads() {
+    let h = start("[upload]\nidle-timeout = 1").await;
+    let sender = h.create(json!({ "filename": "a.bin" })).await;
+    let (tx, rx) = mpsc::unbounded::<Result<Bytes, PayloadError>>();
+    tx.unbounded_send(Ok(Bytes::from_static(b"first"))).unwrap();
+
+    let (sent, received) = join!(
+        async {
+            h.wait(&sender).await;
+            h.send_stream(&sender, rx).await
+        },
+        async { read_body(h.receive(sender.id).await).await },
+    );
+    assert_eq!(sent.status, StatusCode::BAD_REQUEST);
+    assert!(sent.error().starts_with("Transfer timed out"));
+    // the receiver doesn't get a truncated file without noticing
+    assert!(received.is_err());
+    drop(tx);
+}
+
+#[actix_web::test]
+async fn rejects_zero_timeouts() {
+    let h = start("").await;
+
+    for limit in ["idleTimeout", "maxDuration"] {
+        let res = h
+            .call(
+                TestRequest::put()
+                    .uri("/api/v1/transfers")
+                    .insert_header(("x-frachter-token", TOKEN))
+                    .set_json(json!({ "filename": "a.txt", limit: 0 }))
+                    .to_request(),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{limit}");
+    }
+}
+
+#[actix_web::test]
+async fn rejects_a_second_receiver() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.txt" })).await;
+
+    let (second, received) = join!(
+        async {
+            h.wait(&sender).await;
+            let second = h.receive(sender.id).await;
+            h.send(&sender, "only once").await;
+            second
+        },
+        async { read_body(h.receive(sender.id).await).await },
+    );
+    assert_eq!(second.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(received.unwrap(), "only once");
+}
+
+#[actix_web::test]
+async fn rejects_expired_tokens() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.txt" })).await;
+    // past the default leeway of a minute
+    let expired = time::OffsetDateTime::now_utc() - time::Duration::minutes(5);
+
+    let res = h
+        .call(
+            TestRequest::get()
+                .uri("/api/v1/transfer/wait")
+                .insert_header(("x-frachter-token", TOKEN))
+                .cookie(transfer_cookie(TransferRole::Sender, sender.id, expired))
+                .to_request(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(res.error(), "Bad token");
+}
+
+#[actix_web::test]
+async fn rejects_the_receiver_role_for_sending() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.txt" })).await;
+    let expires_at = time::OffsetDateTime::now_utc() + Duration::from_secs(60);
+    let cookie = transfer_cookie(TransferRole::Receiver, sender.id, expires_at);
+
+    let (_, sent) = join!(h.receive(sender.id), async {
+        h.wait(&sender).await;
+        let res = h
+            .call(
+                TestRequest::post()
+                    .uri("/api/v1/transfer/send")
+                    .insert_header(("x-frachter-token", TOKEN))
+                    .cookie(cookie)
+                    .set_payload("nope")
+                    .to_request(),
+            )
+            .await;
+        // let the receiver go
+        h.send(&sender, "").await;
+        res
+    });
+    assert_eq!(sent.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(sent.error(), "Bad token provided");
+}
+
+#[actix_web::test]
+async fn cancels_before_a_receiver_connects() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.txt" })).await;
+
+    let (waited, cancelled) = join!(h.wait(&sender), h.cancel(&sender));
+    assert_eq!(cancelled.status, StatusCode::NO_CONTENT);
+    assert_eq!(waited.error(), "The transfer was closed");
+    assert_eq!(h.status(&sender).await.json()["reason"], "senderCancelled");
+    assert_eq!(h.receive(sender.id).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(h.cancel(&sender).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn cancels_while_sending() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.bin" })).await;
+    let (tx, rx) = mpsc::unbounded::<Result<Bytes, PayloadError>>();
+    tx.unbounded_send(Ok(Bytes::from_static(b"first"))).unwrap();
+
+    let (sent, (received, cancelled)) = join!(
+        async {
+            h.wait(&sender).await;
+            h.send_stream(&sender, rx).await
+        },
+        async {
+            let res = h.receive(sender.id).await;
+            join!(read_body(res), h.cancel(&sender))
+        },
+    );
+    assert_eq!(cancelled.status, StatusCode::NO_CONTENT);
+    assert_eq!(sent.error(), "The transfer was cancelled");
+    assert!(received.is_err());
+    assert_eq!(h.status(&sender).await.json()["reason"], "senderCancelled");
+    drop(tx);
+}
+
+#[actix_web::test]
+async fn declines_before_receiving() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.txt" })).await;
+
+    let (waited, declined) = join!(
+        h.wait(&sender),
+        h.decline(sender.id, json!({ "reason": "wrong file" })),
+    );
+    assert_eq!(declined.status, StatusCode::NO_CONTENT);
+    assert_eq!(waited.error(), "The transfer was closed");
+    let status = h.status(&sender).await.json();
+    assert_eq!(status["reason"], "receiverDeclined");
+    assert_eq!(status["message"], "wrong file");
+    assert_eq!(h.receive(sender.id).await.status(), StatusCode::BAD_REQUEST);
+    let again = h.decline(sender.id, json!({})).await;
+    assert_eq!(again.status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn declines_while_receiving() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.bin" })).await;
+    let (tx, rx) = mpsc::unbounded::<Result<Bytes, PayloadError>>();
+    tx.unbounded_send(Ok(Bytes::from_static(b"first"))).unwrap();
+
+    let (sent, (received, declined)) = join!(
+        async {
+            h.wait(&sender).await;
+            h.send_stream(&sender, rx).await
+        },
+        async {
+            let res = h.receive(sender.id).await;
+            let declined = h
+                .decline(sender.id, json!({ "reason": "not enough storage" }))
+                .await;
+            (read_body(res).await, declined)
+        },
+    );
+    assert_eq!(declined.status, StatusCode::NO_CONTENT);
+    assert_eq!(
+        sent.error(),
+        "The transfer was declined by the receiver: not enough storage"
+    );
+    assert!(received.is_err());
+    let status = h.status(&sender).await.json();
+    assert_eq!(status["reason"], "receiverDeclined");
+    assert_eq!(status["message"], "not enough storage");
+    // the receiver went away as well, but that's not a failure
+    let metrics = h.metrics().await;
+    assert!(metrics.contains("\nfrachter_transfers_declined_total 1\n"));
+    assert!(!metrics.contains("frachter_transfers_failed_total{"));
+    drop(tx);
+}
+
+#[actix_web::test]
+async fn rejects_long_decline_reasons() {
+    let h = start("").await;
+    let sender = h.create(json!({ "filename": "a.txt" })).await;
+
+    let res = h
+        .decline(sender.id, json!({ "reason": "x".repeat(201) }))
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.error(), "Reasons can be up to 200 characters long");
+    let res = h.decline(sender.id, json!({})).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    assert!(h.status(&sender).await.json().get("message").is_none());
+}
+
+#[actix_web::test]
+async fn counts_transfers_in_the_metrics() {
+    let h = start("").await;
+    let completed = h.create(json!({ "filename": "a.txt" })).await;
+    let cancelled = h.create(json!({ "filename": "b.txt" })).await;
+    let metrics = h.metrics().await;
+    assert!(metrics.contains("\nfrachter_transfers_created_total 2\n"));
+    assert!(metrics.contains("\nfrachter_transfers_open 2\n"));
+
+    let (sent, received) = join!(
+        async {
+            h.wait(&completed).await;
+            h.send(&completed, "hello").await
+        },
+        async { read_body(h.receive(completed.id).await).await },
+    );
+    assert_eq!(sent.status, StatusCode::NO_CONTENT);
+    assert_eq!(received.unwrap(), "hello");
+    h.cancel(&cancelled).await;
+    let metrics = h.metrics().await;
+    assert!(metrics.contains("\nfrachter_transfers_completed_total 1\n"));
+    assert!(metrics.contains("\nfrachter_transfers_failed_total{reason=\"senderCancelled\"} 1\n"));
+    assert!(metrics.contains("\nfrachter_transfers_open 0\n"));
+}
+
+#[actix_web::test]
+async fn lets_the_browser_show_inline_pdfs() {
+    let h = start("").await;
+    let pdf = h.create(json!({ "filename": "a.pdf" })).await;
+    let text = h.create(json!({ "filename": "a.txt" })).await;
+
+    // the browser's PDF viewer is a plugin, nothing else may load one
+    for (sender, payload, plugins) in [(&pdf, "%PDF-1.4\n%%EOF\n", true), (&text, "hello", false)] {
+        let (_, res) = join!(
+            async {
+                h.wait(sender).await;
+                h.send(sender, payload).await
+            },
+            h.call_streaming(
+                TestRequest::get()
+                    .uri(&format!("/api/v1/receive/{}?preview=true", sender.id))
+                    .to_request(),
+            ),
+        );
+        let csp = res
+            .headers()
+            .get(header::CONTENT_SECURITY_POLICY)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert!(csp.starts_with("default-src 'none'"), "{csp}");
+        assert_eq!(csp.contains("object-src 'self'"), plugins, "{csp}");
+        assert!(res
+            .headers()
+            .get(header::CONTENT_DISPOSITION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("inline"));
+        read_body(res).await.unwrap();
+    }
+}